@@ -0,0 +1,695 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use itertools::Itertools;
+use strsim::jaro_winkler;
+use tbot::{
+    contexts::{
+        methods::{Callback, Message},
+        Command, DataCallback,
+    },
+    state::StatefulEventLoop,
+    types::{
+        callback::Origin,
+        chat, file,
+        input_file::{Animation, Photo},
+        keyboard::inline::{Button, ButtonKind, Keyboard},
+        message::Kind,
+    },
+    Bot,
+};
+use tokio::{sync::Mutex, time};
+use tracing::{info, warn};
+
+use crate::{
+    error::{answer_with_error, reply_with_error, ApiError},
+    prefetch::{Pool, PoolConfig},
+    ResultExt,
+};
+
+/// Prefix of the callback data of buttons which send an animal.
+const ANIMAL_PREFIX: &str = "animal:";
+/// Prefix of the callback data of variant list page buttons.
+const PAGE_PREFIX: &str = "variants:";
+/// Matches at least this good are picked without asking...
+const AUTO_SELECT_SCORE: f64 = 0.85;
+/// ...if no other match is within this distance.
+const AUTO_SELECT_MARGIN: f64 = 0.1;
+/// Worse matches aren't suggested.
+const MIN_SCORE: f64 = 0.8;
+const MAX_SUGGESTIONS: usize = 8;
+/// Variants (with all their sub-variants) per list page
+const VARIANTS_PER_PAGE: usize = 8;
+/// Sub-variant buttons per keyboard row
+const BUTTONS_PER_ROW: usize = 4;
+/// How long to wait after the first failed refresh of a variant list;
+/// doubled after each further failure, up to the refresh interval.
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Photo,
+    Animation,
+}
+
+/// Something cute to send.
+#[derive(Debug, Clone)]
+pub struct Media {
+    pub kind: MediaKind,
+    pub url: String,
+    /// Identifies the media when caching its file ID
+    pub id: String,
+    /// What we know about the animal, e.g. its breed
+    pub caption: Option<String>,
+    /// The variant the animal is of, so "another" sends one of the same
+    pub variant: Option<String>,
+}
+impl Media {
+    /// Media identified by its URL, which is a GIF or else a photo.
+    pub fn from_url(url: String) -> Self {
+        let kind = if url.to_ascii_lowercase().ends_with(".gif") {
+            MediaKind::Animation
+        } else {
+            MediaKind::Photo
+        };
+        Media {
+            kind,
+            id: url.clone(),
+            url,
+            caption: None,
+            variant: None,
+        }
+    }
+}
+
+/// An API serving random pictures or animations of some animal.
+#[async_trait]
+pub trait AnimalProvider: Send + Sync {
+    async fn fetch_random(&self) -> Result<Media, ApiError>;
+    /// The variants (e.g. breeds) animals can be filtered by; empty if there
+    /// is no fixed list or it isn't known right now.
+    fn variants(&self) -> Arc<BTreeSet<String>> {
+        Arc::default()
+    }
+    /// Whether multi-word variants are "<sub-variant> <variant>" and should
+    /// be listed by variant; otherwise they're names like "maine coon".
+    fn has_sub_variants(&self) -> bool {
+        false
+    }
+    /// Whether animals can be asked for by variant at all, i.e. whether the
+    /// command takes an argument. Others get a random animal either way.
+    fn has_variants(&self) -> bool {
+        false
+    }
+    /// Fetches a random animal of one of the `variants`, or `None` if the
+    /// variant doesn't exist.
+    async fn fetch_variant(&self, variant: &str) -> Result<Option<Media>, ApiError> {
+        let _ = variant;
+        Ok(None)
+    }
+    /// Shown when `fetch_variant` found nothing.
+    fn not_found(&self) -> &'static str {
+        "Not found!"
+    }
+}
+
+pub struct Animal {
+    command: &'static str,
+    /// Lists the variants, e.g. `/breeds`
+    variants_command: Option<&'static str>,
+    /// What the list of variants is called, e.g. "Doggo breeds"
+    variants_title: &'static str,
+    provider: Arc<dyn AnimalProvider>,
+    pool: Arc<Pool<Media>>,
+    id_map: Mutex<HashMap<String, file::Id>>,
+}
+
+impl Animal {
+    /// Creates the animal and starts prefetching random ones.
+    pub fn new(
+        command: &'static str,
+        provider: impl AnimalProvider + 'static,
+        pool_config: PoolConfig,
+    ) -> Self {
+        let provider: Arc<dyn AnimalProvider> = Arc::new(provider);
+        let pool = Pool::spawn(command, pool_config, {
+            let provider = provider.clone();
+            move || {
+                let provider = provider.clone();
+                async move { provider.fetch_random().await }
+            }
+        });
+        Animal {
+            command,
+            variants_command: None,
+            variants_title: "Variants",
+            provider,
+            pool,
+            id_map: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fetches an animal (of `variant`); `None` if the variant doesn't exist.
+    async fn fetch(&self, variant: Option<&str>) -> Result<Option<Media>, ApiError> {
+        match variant {
+            Some(variant) => self.provider.fetch_variant(variant).await,
+            // Only unfiltered animals are prefetched
+            None => match self.pool.take().await {
+                Some(media) => Ok(Some(media)),
+                None => self.provider.fetch_random().await.map(Some),
+            },
+        }
+    }
+
+    /// Adds a command listing the variants.
+    pub fn variants_command(mut self, command: &'static str, title: &'static str) -> Self {
+        self.variants_command = Some(command);
+        self.variants_title = title;
+        self
+    }
+}
+
+/// All animals, by command.
+pub struct Animals {
+    animals: BTreeMap<&'static str, Animal>,
+    /// Whether to send what we know about an animal along with it
+    captions: bool,
+}
+
+impl Animals {
+    pub fn new(captions: bool) -> Self {
+        Animals {
+            animals: BTreeMap::new(),
+            captions,
+        }
+    }
+    pub fn add(&mut self, animal: Animal) {
+        self.animals.insert(animal.command, animal);
+    }
+}
+
+/// Registers the commands of all animals (and their buttons).
+pub fn register<S>(bot: StatefulEventLoop<S>, animals: Animals) -> StatefulEventLoop<Animals> {
+    let commands: Vec<_> = animals
+        .animals
+        .values()
+        .map(|animal| (animal.command, animal.variants_command))
+        .collect();
+    let mut bot = bot.with_other_state(animals);
+    for (command, variants_command) in commands {
+        bot.command(command, move |ctx, animals| {
+            animal_handler(ctx, animals, command)
+        });
+        if let Some(variants_command) = variants_command {
+            bot.command(variants_command, move |ctx, animals| {
+                variants_handler(ctx, animals, command)
+            });
+        }
+    }
+    bot.data_callback(callback_handler);
+    bot
+}
+
+async fn animal_handler(ctx: Arc<Command>, animals: Arc<Animals>, command: &'static str) {
+    let animal = &animals.animals[command];
+    let media = if ctx.text.value.trim().is_empty() || !animal.provider.has_variants() {
+        animal.fetch(None).await
+    } else {
+        match match_variant(&animal.provider.variants(), &ctx.text.value) {
+            VariantMatch::Found(variant) => animal.fetch(Some(&variant)).await,
+            VariantMatch::Ambiguous(suggestions) => {
+                ctx.send_message_in_reply("Did you mean any of these?")
+                    .reply_markup(suggestion_keyboard(command, suggestions))
+                    .call()
+                    .await
+                    .log_err();
+                return;
+            }
+            VariantMatch::NotFound => Ok(None),
+        }
+    };
+    let result = match media {
+        Ok(Some(media)) => send_media(&ctx.bot, ctx.chat.id, animal, media, animals.captions).await,
+        Ok(None) => {
+            ctx.send_message_in_reply(animal.provider.not_found())
+                .call()
+                .await
+                .log_err();
+            return;
+        }
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        reply_with_error(&ctx, error).await;
+    }
+}
+
+/// Lists the variants matching the filter (if any), a few per page.
+async fn variants_handler(ctx: Arc<Command>, animals: Arc<Animals>, command: &'static str) {
+    let animal = &animals.animals[command];
+    let (text, keyboard) = variants_page(animal, ctx.text.value.trim(), 0);
+    ctx.send_message_in_reply(text)
+        .reply_markup(keyboard)
+        .call()
+        .await
+        .log_err();
+}
+
+/// Handles the buttons of "did you mean" messages, variant lists and animals.
+async fn callback_handler(ctx: Arc<DataCallback>, animals: Arc<Animals>) {
+    let msg = match &ctx.origin {
+        Origin::Message(msg) => msg,
+        Origin::Inline(_) => return,
+    };
+    let (action, data) = if let Some(data) = ctx.data.strip_prefix(ANIMAL_PREFIX) {
+        (ANIMAL_PREFIX, data)
+    } else if let Some(data) = ctx.data.strip_prefix(PAGE_PREFIX) {
+        (PAGE_PREFIX, data)
+    } else {
+        return;
+    };
+    let (command, data) = data.split_once(':').unwrap_or((data, ""));
+    let animal = match animals.animals.get(command) {
+        Some(animal) => animal,
+        None => return,
+    };
+    if action == ANIMAL_PREFIX {
+        // "Another" buttons of animals without a variant have none
+        let variant = Some(data).filter(|variant| !variant.is_empty());
+        let result = match animal.fetch(variant).await {
+            Ok(Some(media)) => {
+                send_media(&ctx.bot, msg.chat.id, animal, media, animals.captions).await
+            }
+            Ok(None) => {
+                let msg = animal.provider.not_found();
+                ctx.notify(msg).call().await.log_err();
+                return;
+            }
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(()) => ctx.ignore().call().await.log_err(),
+            Err(error) => answer_with_error(&ctx, error).await,
+        }
+    } else {
+        let (page, filter) = data.split_once(':').unwrap_or((data, ""));
+        let (text, keyboard) = variants_page(animal, filter, page.parse().unwrap_or(0));
+        ctx.ignore().call().await.log_err();
+        ctx.bot
+            .edit_message_text(msg.chat.id, msg.id, text)
+            .reply_markup(keyboard)
+            .call()
+            .await
+            .log_err_msg("error turning variants page");
+    }
+}
+
+fn animal_button(command: &str, label: &str, variant: &str) -> Button {
+    let data = format!("{}{}:{}", ANIMAL_PREFIX, command, variant);
+    Button::new(label, ButtonKind::CallbackData(data))
+}
+
+fn suggestion_keyboard(command: &str, variants: Vec<String>) -> Keyboard {
+    let buttons = variants
+        .iter()
+        .map(|variant| vec![animal_button(command, variant, variant)])
+        .collect::<Vec<_>>();
+    Keyboard::new(buttons)
+}
+
+/// Sends the animal with an "another one" button, caching its file ID.
+async fn send_media(
+    bot: &Bot,
+    chat: chat::Id,
+    animal: &Animal,
+    media: Media,
+    captions: bool,
+) -> Result<(), ApiError> {
+    let cached = animal.id_map.lock().await.get(&media.id).cloned();
+    let caption = media.caption.filter(|_| captions);
+    let variant = media.variant.as_deref().unwrap_or_default();
+    let another = Keyboard::new(vec![vec![animal_button(
+        animal.command,
+        "🔁 another",
+        variant,
+    )]]);
+    macro_rules! send {
+        ($method:ident, $input:expr) => {{
+            let mut input = $input;
+            if let Some(caption) = &caption {
+                input = input.caption(caption.as_str());
+            }
+            bot.$method(chat, input)
+                .reply_markup(another)
+                .call()
+                .await?
+        }};
+    }
+    let msg = match (media.kind, cached) {
+        (MediaKind::Photo, Some(id)) => send!(send_photo, Photo::with_id(id)),
+        (MediaKind::Photo, None) => send!(send_photo, Photo::with_url(&media.url)),
+        (MediaKind::Animation, Some(id)) => send!(send_animation, Animation::with_id(id)),
+        (MediaKind::Animation, None) => send!(send_animation, Animation::with_url(&media.url)),
+    };
+    let file_id = match msg.kind {
+        Kind::Photo { photo, .. } => photo.into_iter().next().map(|photo| photo.file_id),
+        Kind::Animation { animation, .. } => Some(animation.file_id),
+        // Telegram turns animations with audio into videos
+        Kind::Video { video, .. } => Some(video.file_id),
+        _ => None,
+    };
+    if let Some(file_id) = file_id {
+        animal.id_map.lock().await.insert(media.id, file_id);
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum VariantMatch {
+    /// An exact match or one that is clearly the best
+    Found(String),
+    Ambiguous(Vec<String>),
+    NotFound,
+}
+
+/// Finds the variant the user most likely meant, allowing typos and either
+/// word order for sub-variants ("golden retriever", "retriever golden",
+/// "retriever/golden").
+fn match_variant(variants: &BTreeSet<String>, query: &str) -> VariantMatch {
+    let query = query
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '/' || c == '-')
+        .filter(|word| !word.is_empty())
+        .join(" ");
+    // Without a list of variants, all we can do is ask the API
+    if variants.is_empty() || variants.contains(&query) {
+        return VariantMatch::Found(query);
+    }
+    let reversed = query.rsplit(' ').join(" ");
+    if variants.contains(&reversed) {
+        return VariantMatch::Found(reversed);
+    }
+    let mut scored: Vec<(f64, &String)> = variants
+        .iter()
+        .map(|variant| (match_score(&query, variant), variant))
+        .filter(|(score, _)| *score >= MIN_SCORE)
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    match scored.as_slice() {
+        [] => VariantMatch::NotFound,
+        [(best, variant), rest @ ..]
+            if *best >= AUTO_SELECT_SCORE
+                && rest
+                    .first()
+                    .map_or(true, |(second, _)| best - second >= AUTO_SELECT_MARGIN) =>
+        {
+            VariantMatch::Found(variant.to_string())
+        }
+        [(best, _), ..] => {
+            let best = *best;
+            VariantMatch::Ambiguous(
+                scored
+                    .into_iter()
+                    .take_while(|(score, _)| best - score < AUTO_SELECT_MARGIN)
+                    .take(MAX_SUGGESTIONS)
+                    .map(|(_, variant)| variant.clone())
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// How similar the query is to the variant's full name or any of its words,
+/// so "retriever" matches all retrievers equally well.
+fn match_score(query: &str, variant: &str) -> f64 {
+    variant
+        .split(' ')
+        .map(|word| jaro_winkler(query, word))
+        .fold(jaro_winkler(query, variant), f64::max)
+}
+
+/// Variants with their sub-variants (if `sub_variants`), keeping only those
+/// matching `filter` (and all sub-variants of a variant that matches).
+fn group_variants<'a>(
+    variants: &'a BTreeSet<String>,
+    filter: &str,
+    sub_variants: bool,
+) -> BTreeMap<&'a str, Vec<&'a str>> {
+    let filter = filter.to_lowercase();
+    let mut groups: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for name in variants {
+        let (sub_variant, variant) = match name.rsplit_once(' ') {
+            Some((sub_variant, variant)) if sub_variants => (Some(sub_variant), variant),
+            _ => (None, name.as_str()),
+        };
+        if !variant.contains(&filter) && !name.contains(&filter) {
+            continue;
+        }
+        let subs = groups.entry(variant).or_default();
+        subs.extend(sub_variant);
+    }
+    groups
+}
+
+/// The text and buttons of a variant list page; `page` is clamped to the last.
+fn variants_page(animal: &Animal, filter: &str, page: usize) -> (String, Keyboard) {
+    let variants = animal.provider.variants();
+    list_page(
+        animal.command,
+        animal.variants_title,
+        &variants,
+        animal.provider.has_sub_variants(),
+        filter,
+        page,
+    )
+}
+
+fn list_page(
+    command: &str,
+    title: &str,
+    variants: &BTreeSet<String>,
+    sub_variants: bool,
+    filter: &str,
+    page: usize,
+) -> (String, Keyboard) {
+    let groups = group_variants(variants, filter, sub_variants);
+    if groups.is_empty() {
+        // "breeds" for "Doggo breeds"
+        let noun = title.rsplit(' ').next().unwrap_or(title).to_lowercase();
+        let text = if variants.is_empty() {
+            format!("I don't know any {} right now, try again later.", noun)
+        } else {
+            format!("No {} match \"{}\".", noun, filter)
+        };
+        return (text, Keyboard::new(Vec::new()));
+    }
+    let pages = (groups.len() - 1) / VARIANTS_PER_PAGE + 1;
+    let page = page.min(pages - 1);
+    let mut text = format!("{} (page {}/{}):\n\n", title, page + 1, pages);
+    let mut rows = Vec::new();
+    for (variant, subs) in groups
+        .into_iter()
+        .skip(page * VARIANTS_PER_PAGE)
+        .take(VARIANTS_PER_PAGE)
+    {
+        rows.push(vec![animal_button(command, variant, variant)]);
+        if subs.is_empty() {
+            text.push_str(&format!("{}\n", variant));
+            continue;
+        }
+        text.push_str(&format!("{}: {}\n", variant, subs.iter().join(", ")));
+        for chunk in subs.chunks(BUTTONS_PER_ROW) {
+            let row = chunk
+                .iter()
+                .map(|sub| animal_button(command, sub, &format!("{} {}", sub, variant)))
+                .collect();
+            rows.push(row);
+        }
+    }
+    text.push_str("\nTap one to get a picture!");
+    // The filter is part of a variant name here, so it fits into the callback data
+    let nav = |label: &str, page: usize| {
+        let data = format!("{}{}:{}:{}", PAGE_PREFIX, command, page, filter);
+        Button::new(label, ButtonKind::CallbackData(data))
+    };
+    let mut nav_row = Vec::new();
+    if page > 0 {
+        nav_row.push(nav("◀️ Previous", page - 1));
+    }
+    if page + 1 < pages {
+        nav_row.push(nav("Next ▶️", page + 1));
+    }
+    if !nav_row.is_empty() {
+        rows.push(nav_row);
+    }
+    (text, Keyboard::new(rows))
+}
+
+/// Calls `refresh` every `interval` to update a provider's variants, retrying
+/// with backoff when it fails (and right away unless some are `known` yet).
+/// `refresh` returns how many variants there are now.
+pub async fn refresh_variants<F, Fut, E>(name: &str, interval: Duration, known: bool, refresh: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<usize, E>>,
+    E: fmt::Debug,
+{
+    let mut delay = if known { interval } else { REFRESH_RETRY_DELAY };
+    loop {
+        time::sleep(delay).await;
+        match refresh().await {
+            Ok(count) => {
+                info!(name, count, "Refreshed variants");
+                delay = interval;
+            }
+            Err(error) => {
+                delay = if delay >= interval {
+                    REFRESH_RETRY_DELAY
+                } else {
+                    (delay * 2).min(interval)
+                };
+                warn!(?error, name, retry_in = ?delay, "Error refreshing variants");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::MockServer;
+
+    use super::*;
+    use crate::{
+        cat::TheCatApi,
+        doggo::fetch_breeds,
+        test_util::{fixture, mount, test_http},
+    };
+
+    async fn breeds() -> BTreeSet<String> {
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            "/breeds/list/all",
+            200,
+            &fixture("dog_ceo/breeds_list_all.json"),
+        )
+        .await;
+        fetch_breeds(&test_http(), &server.uri()).await.unwrap()
+    }
+
+    async fn cat_breeds() -> Arc<BTreeSet<String>> {
+        let server = MockServer::start().await;
+        mount(&server, "/breeds", 200, &fixture("thecatapi/breeds.json")).await;
+        TheCatApi::new(test_http(), server.uri(), None)
+            .await
+            .variants()
+    }
+
+    #[tokio::test]
+    async fn variants_are_matched_fuzzily() {
+        let breeds = breeds().await;
+        let found = |breed: &str| VariantMatch::Found(breed.into());
+        assert_eq!(match_variant(&breeds, "Husky"), found("husky"));
+        assert_eq!(match_variant(&breeds, "huskie"), found("husky"));
+        assert_eq!(match_variant(&breeds, "beagel"), found("beagle"));
+        assert_eq!(match_variant(&breeds, "golden"), found("golden retriever"));
+        assert_eq!(
+            match_variant(&breeds, "goldne  retriever"),
+            found("golden retriever")
+        );
+        assert_eq!(
+            match_variant(&breeds, "Retriever Golden"),
+            found("golden retriever")
+        );
+        assert_eq!(
+            match_variant(&breeds, "retriever/golden"),
+            found("golden retriever")
+        );
+        assert_eq!(match_variant(&breeds, "cat"), VariantMatch::NotFound);
+        match match_variant(&breeds, "retriver") {
+            VariantMatch::Ambiguous(suggestions) => assert_eq!(
+                suggestions[..4],
+                [
+                    "chesapeake retriever",
+                    "curly retriever",
+                    "flatcoated retriever",
+                    "golden retriever"
+                ]
+            ),
+            other => panic!("expected suggestions, got {:?}", other),
+        }
+        assert_eq!(
+            match_variant(&BTreeSet::new(), "Whatever Dog"),
+            found("whatever dog")
+        );
+    }
+
+    #[tokio::test]
+    async fn variants_are_grouped_and_paginated() {
+        let breeds = breeds().await;
+        let groups = group_variants(&breeds, "", true);
+        assert_eq!(groups.len(), 6);
+        assert_eq!(groups["spaniel"], ["cocker", "irish", "welsh"]);
+        assert!(groups["akita"].is_empty());
+        let (text, _) = list_page("doggo", "Doggo breeds", &breeds, true, "", 0);
+        assert!(
+            text.starts_with("Doggo breeds (page 1/1):\n\nakita\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("\nretriever: chesapeake, curly, flatcoated, golden\n"));
+
+        let groups = group_variants(&breeds, "Irish", true);
+        assert_eq!(groups["spaniel"], ["irish"]);
+        assert_eq!(groups["terrier"], ["irish"]);
+        assert_eq!(
+            group_variants(&breeds, "retriever", true)["retriever"].len(),
+            4
+        );
+
+        let many: BTreeSet<String> = (0..20).map(|i| format!("breed{:02}", i)).collect();
+        let (text, _) = list_page("doggo", "Breeds", &many, true, "", 1);
+        assert!(
+            text.starts_with("Breeds (page 2/3):\n\nbreed08\n"),
+            "{}",
+            text
+        );
+        let (text, _) = list_page("doggo", "Breeds", &many, true, "", 42);
+        assert!(
+            text.starts_with("Breeds (page 3/3):\n\nbreed16\n"),
+            "{}",
+            text
+        );
+
+        let (text, _) = list_page("doggo", "Breeds", &breeds, true, "cat", 0);
+        assert_eq!(text, "No breeds match \"cat\".");
+        let (text, _) = list_page("flausch", "Variants", &BTreeSet::new(), false, "", 0);
+        assert_eq!(
+            text,
+            "I don't know any variants right now, try again later."
+        );
+    }
+
+    #[tokio::test]
+    async fn names_without_sub_variants_are_listed_whole() {
+        let breeds = cat_breeds().await;
+        let (text, _) = list_page("cat", "Cat breeds", &breeds, false, "", 0);
+        assert_eq!(
+            text,
+            "Cat breeds (page 1/1):\n\n\
+             abyssinian\nbritish shorthair\neuropean burmese\nmaine coon\n\n\
+             Tap one to get a picture!"
+        );
+        let (text, _) = list_page("cat", "Cat breeds", &breeds, false, "coon", 0);
+        assert_eq!(
+            text,
+            "Cat breeds (page 1/1):\n\nmaine coon\n\nTap one to get a picture!"
+        );
+    }
+}
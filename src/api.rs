@@ -0,0 +1,453 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use rand::{prelude::SliceRandom, thread_rng};
+use serde::Deserialize;
+use serde_json::Value;
+use tbot::{contexts::methods::Message, state::StatefulEventLoop, types::parameters::Text};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{
+    bot::load_file_lines,
+    error::{reply_with_error, ApiError},
+    http::Http,
+    prefetch::{Pool, PoolConfig},
+    ResultExt,
+};
+
+/// A command which answers with data from a JSON API, e.g. a random joke.
+#[derive(Debug, Deserialize)]
+pub struct ApiCommand {
+    /// Other names the command can be called by
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Shown in the help message
+    pub descr: String,
+    /// Tried in order until one of them answers
+    pub providers: Vec<Provider>,
+    /// A file in `res/txt/` to pick a random line from when all providers fail
+    #[serde(default)]
+    pub corpus: Option<PathBuf>,
+    #[serde(skip)]
+    corpus_lines: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Provider {
+    pub url: String,
+    /// Overrides the default HTTP timeout for this provider
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Maps names usable in the template to JSON pointers (`/value/joke`)
+    /// or simple JSONPath expressions (`$.value.joke`, `$.items[0].text`)
+    pub fields: BTreeMap<String, String>,
+    /// The answer, with `{name}` replaced by the extracted fields
+    pub template: String,
+    #[serde(default)]
+    pub markup: Markup,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Markup {
+    #[default]
+    Plain,
+    /// The template is MarkdownV2; extracted fields are escaped accordingly
+    MarkdownV2,
+}
+
+pub type ApiCommands = BTreeMap<String, ApiCommand>;
+
+/// A rendered answer, ready to be sent.
+#[derive(Debug)]
+pub struct Answer {
+    pub text: String,
+    pub markup: Markup,
+}
+impl From<Answer> for Text {
+    fn from(answer: Answer) -> Self {
+        match answer.markup {
+            Markup::Plain => Text::with_plain(answer.text),
+            Markup::MarkdownV2 => Text::with_markdown_v2(answer.text),
+        }
+    }
+}
+
+pub async fn load_commands(path: &Path) -> Result<ApiCommands> {
+    let json = fs::read(path)
+        .await
+        .wrap_err_with(|| format!("Error reading API commands from {:?}", path))?;
+    let mut commands: ApiCommands =
+        serde_json::from_slice(&json).wrap_err("Error parsing API commands")?;
+    let base = Path::new("res/txt/");
+    for def in commands.values_mut() {
+        for provider in &mut def.providers {
+            provider.url = expand_env(&provider.url);
+        }
+        if let Some(corpus) = &def.corpus {
+            def.corpus_lines = load_file_lines(base.join(corpus)).await?;
+        }
+    }
+    Ok(commands)
+}
+
+/// Replaces `${VAR}` and `${VAR:-default}` with the env var's value, so
+/// providers can be pointed at mirrors or local stand-ins.
+fn expand_env(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let after = &rest[start + 2..];
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        let (var, default) = after[..end].split_once(":-").unwrap_or((&after[..end], ""));
+        result.push_str(&rest[..start]);
+        result.push_str(&env::var(var).unwrap_or_else(|_| default.to_string()));
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// tbot wants command names to live forever; they're only loaded once anyway.
+pub fn leak(name: &str) -> &'static str {
+    Box::leak(name.to_string().into_boxed_str())
+}
+
+pub fn register(
+    bot: &mut StatefulEventLoop<Http>,
+    commands: ApiCommands,
+    http: &Http,
+    pool_config: PoolConfig,
+) {
+    for (cmd, def) in commands {
+        let names: Vec<&'static str> = std::iter::once(&cmd)
+            .chain(def.aliases.iter())
+            .map(|name| leak(name))
+            .collect();
+        let def = Arc::new(def);
+        let pool = Pool::spawn(names[0], pool_config, {
+            let http = http.clone();
+            let def = def.clone();
+            move || {
+                let http = http.clone();
+                let def = def.clone();
+                async move { fetch_providers(&http, &def).await }
+            }
+        });
+        bot.commands(names, move |ctx, http| {
+            let def = def.clone();
+            let pool = pool.clone();
+            async move {
+                let result = match pool.take().await {
+                    Some(answer) => Ok(answer),
+                    None => fetch(&http, &def).await,
+                };
+                match result {
+                    Ok(answer) => ctx.send_message(answer).call().await.log_err(),
+                    Err(error) => reply_with_error(&ctx, error).await,
+                }
+            }
+        });
+    }
+}
+
+/// Asks all providers in order; if none of them answers, falls back to
+/// the corpus (if there is one).
+pub async fn fetch(http: &Http, def: &ApiCommand) -> Result<Answer, ApiError> {
+    let error = match fetch_providers(http, def).await {
+        Ok(answer) => return Ok(answer),
+        Err(error) => error,
+    };
+    match def.corpus_lines.choose(&mut thread_rng()) {
+        Some(line) => Ok(Answer {
+            text: line.clone(),
+            markup: Markup::Plain,
+        }),
+        None => Err(error),
+    }
+}
+
+/// Like [`fetch`], but without the corpus fallback.
+pub async fn fetch_providers(http: &Http, def: &ApiCommand) -> Result<Answer, ApiError> {
+    let mut last_error = None;
+    for provider in &def.providers {
+        match fetch_provider(http, provider).await {
+            Ok(answer) => return Ok(answer),
+            Err(error) => {
+                warn!(?error, url = %provider.url, "provider failed");
+                last_error = Some(error);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| ApiError::Request(eyre!("no providers configured"))))
+}
+
+async fn fetch_provider(http: &Http, provider: &Provider) -> Result<Answer, ApiError> {
+    let mut request = http
+        .get(&provider.url)
+        .timeout(provider.timeout_secs.map(Duration::from_secs));
+    for (name, value) in &provider.headers {
+        request = request.header(name, value);
+    }
+    let json = request.json::<Value>().await?.body;
+    let mut values = BTreeMap::new();
+    for (name, expr) in &provider.fields {
+        let value = extract(&json, expr).ok_or_else(|| {
+            ApiError::Response(eyre!(
+                "field not found: {:?} at {:?} (json: {:?})",
+                name,
+                expr,
+                json
+            ))
+        })?;
+        let value = match value {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        };
+        let value = match provider.markup {
+            Markup::Plain => value,
+            Markup::MarkdownV2 => escape_markdown_v2(&value),
+        };
+        values.insert(name.as_str(), value);
+    }
+    Ok(Answer {
+        text: render(&provider.template, &values),
+        markup: provider.markup,
+    })
+}
+
+/// Looks up a JSON pointer or a (simple) JSONPath expression.
+pub fn extract<'a>(json: &'a Value, expr: &str) -> Option<&'a Value> {
+    let path = match expr.strip_prefix('$') {
+        Some(path) => path,
+        None => return json.pointer(expr),
+    };
+    let mut current = json;
+    for segment in path
+        .split(|c| c == '.' || c == '[')
+        .filter(|s| !s.is_empty())
+    {
+        current = match segment.strip_suffix(']') {
+            Some(index) => current.get(index.parse::<usize>().ok()?)?,
+            None => current.get(segment)?,
+        };
+    }
+    Some(current)
+}
+
+/// Replaces `{name}` with the value of `name` in a single pass, so values
+/// which look like placeholders themselves are left alone. Unknown
+/// placeholders are kept as they are.
+pub fn render(template: &str, values: &BTreeMap<&str, String>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        result.push_str(&rest[..start]);
+        match after
+            .find('}')
+            .and_then(|end| Some((values.get(&after[..end])?, end)))
+        {
+            Some((value, end)) => {
+                result.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn escape_markdown_v2(text: &str) -> String {
+    const SPECIAL: &str = "_*[]()~`>#+-=|{}.!\\";
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if SPECIAL.contains(ch) {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::test_util::{fixture, mount, test_http};
+
+    fn command(server: &MockServer, providers: Value) -> ApiCommand {
+        let providers = serde_json::to_string(&providers)
+            .unwrap()
+            .replace("${SERVER}", &server.uri());
+        serde_json::from_value(json!({
+            "descr": "test",
+            "providers": serde_json::from_str::<Value>(&providers).unwrap(),
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dadjoke_sends_headers_and_extracts_joke() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .and(header("Accept", "application/json"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(fixture("icanhazdadjoke/joke.json"), "application/json"),
+            )
+            .mount(&server)
+            .await;
+        let def = command(
+            &server,
+            json!([{
+                "url": "${SERVER}/",
+                "headers": { "Accept": "application/json" },
+                "fields": { "joke": "$.joke" },
+                "template": "{joke}",
+            }]),
+        );
+        let answer = fetch(&test_http(), &def).await.unwrap();
+        assert_eq!(
+            answer.text,
+            "My dog used to chase people on a bike a lot. It got so bad I had to take his bike away."
+        );
+        assert_eq!(answer.markup, Markup::Plain);
+    }
+
+    #[tokio::test]
+    async fn template_combines_fields() {
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            "/joke",
+            200,
+            r#"{"setup": "Why?", "punchline": "Because."}"#,
+        )
+        .await;
+        let def = command(
+            &server,
+            json!([{
+                "url": "${SERVER}/joke",
+                "fields": { "setup": "/setup", "punchline": "/punchline" },
+                "template": "{setup}\n\n||{punchline}||",
+                "markup": "markdown_v2",
+            }]),
+        );
+        let answer = fetch(&test_http(), &def).await.unwrap();
+        assert_eq!(answer.text, "Why?\n\n||Because\\.||");
+        assert_eq!(answer.markup, Markup::MarkdownV2);
+    }
+
+    #[tokio::test]
+    async fn missing_field_is_a_response_error() {
+        let server = MockServer::start().await;
+        mount(&server, "/random", 200, &fixture("chucknorris/random.json")).await;
+        let def = command(
+            &server,
+            json!([{
+                "url": "${SERVER}/random",
+                "fields": { "joke": "/value/joke" },
+                "template": "{joke}",
+            }]),
+        );
+        match fetch(&test_http(), &def).await {
+            Err(ApiError::Response(error)) => {
+                assert!(error.to_string().starts_with("field not found: \"joke\""))
+            }
+            other => panic!("expected response error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_a_response_error() {
+        let server = MockServer::start().await;
+        mount(&server, "/random", 200, &fixture("malformed.json")).await;
+        let def = command(
+            &server,
+            json!([{ "url": "${SERVER}/random", "fields": {}, "template": "" }]),
+        );
+        let result = fetch(&test_http(), &def).await;
+        assert!(matches!(result, Err(ApiError::Response(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_provider_and_corpus() {
+        let server = MockServer::start().await;
+        mount(&server, "/broken", 500, "{}").await;
+        mount(&server, "/random", 200, &fixture("chucknorris/random.json")).await;
+        let mut def = command(
+            &server,
+            json!([
+                { "url": "${SERVER}/broken", "fields": {}, "template": "broken" },
+                { "url": "${SERVER}/random", "fields": { "joke": "/value" }, "template": "{joke}" },
+            ]),
+        );
+        let answer = fetch(&test_http(), &def).await.unwrap();
+        assert_eq!(
+            answer.text,
+            "Chuck Norris can unit test an entire application with a single assert."
+        );
+
+        def.providers.remove(1);
+        def.corpus_lines = vec!["Chuck Norris counted to infinity. Twice.".into()];
+        let answer = fetch(&test_http(), &def).await.unwrap();
+        assert_eq!(answer.text, "Chuck Norris counted to infinity. Twice.");
+        assert!(fetch_providers(&test_http(), &def).await.is_err());
+    }
+
+    #[test]
+    fn extract_supports_pointers_and_jsonpath() {
+        let json = json!({ "value": { "joke": "a" }, "items": [{ "text": "b" }] });
+        assert_eq!(extract(&json, "/value/joke"), Some(&json!("a")));
+        assert_eq!(extract(&json, "$.value.joke"), Some(&json!("a")));
+        assert_eq!(extract(&json, "$.items[0].text"), Some(&json!("b")));
+        assert_eq!(extract(&json, "$.items[1].text"), None);
+        assert_eq!(extract(&json, "/missing"), None);
+    }
+
+    #[test]
+    fn expand_env_uses_defaults() {
+        assert_eq!(
+            expand_env("${GODFISH_TEST_UNSET_URL:-http://localhost}/x"),
+            "http://localhost/x"
+        );
+        assert_eq!(expand_env("no vars"), "no vars");
+    }
+
+    #[test]
+    fn render_substitutes_once() {
+        let values: BTreeMap<_, _> = vec![("a", "{b}".to_string()), ("b", "x".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(render("{a} {b}", &values), "{b} x");
+        assert_eq!(render("{b}{a}", &values), "x{b}");
+        assert_eq!(render("{{b}} {c} {", &values), "{x} {c} {");
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    mem,
+    io, mem,
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -8,19 +8,24 @@ use std::{
 use color_eyre::eyre::{Result, WrapErr};
 use rand::{prelude::SliceRandom, thread_rng};
 use tbot::{
-    contexts::methods::Message,
+    contexts::{methods::Message, Command},
     types::{
         file,
-        input_file::{Photo, Voice},
-        message::Kind,
-        Message as Msg,
+        input_file::{Animation, Document, GroupMedia, Photo, Sticker, Video, VideoNote, Voice},
+        message::{self, Kind},
+        user, Message as Msg,
     },
     EventLoop,
 };
 use tokio::{fs, sync::Mutex};
 use tracing::{error, info};
 
-use crate::ResultExt;
+use crate::{
+    api::ApiCommands,
+    overlay::{self, Templates},
+    preprocess::{preprocess_images, ImageLimits},
+    ResultExt,
+};
 
 struct SoundDef {
     descr: Option<String>,
@@ -35,13 +40,60 @@ struct RandImgDef {
     descr: String,
     folder: &'static Path,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MediaKind {
+    Animation,
+    Video,
+    VideoNote,
+    Sticker,
+    Document,
+}
+impl MediaKind {
+    /// Where files and folders of this kind are looked up.
+    fn base_dir(self) -> &'static Path {
+        Path::new(match self {
+            MediaKind::Animation => "res/gifs/",
+            MediaKind::Video => "res/videos/",
+            MediaKind::VideoNote => "res/video_notes/",
+            MediaKind::Sticker => "res/stickers/",
+            MediaKind::Document => "res/documents/",
+        })
+    }
+    fn default_descr(self, random: bool) -> &'static str {
+        match (self, random) {
+            (MediaKind::Animation, false) => "Get a GIF",
+            (MediaKind::Animation, true) => "Get a random GIF",
+            (MediaKind::Video, false) => "Get a video",
+            (MediaKind::Video, true) => "Get a random video",
+            (MediaKind::VideoNote, false) => "Get a video message",
+            (MediaKind::VideoNote, true) => "Get a random video message",
+            (MediaKind::Sticker, false) => "Get a sticker",
+            (MediaKind::Sticker, true) => "Get a random sticker",
+            (MediaKind::Document, false) => "Get a file",
+            (MediaKind::Document, true) => "Get a random file",
+        }
+    }
+}
+enum MediaSource {
+    /// A single file, relative to the kind's base dir
+    File(&'static Path),
+    /// A folder to pick random files from, relative to the kind's base dir
+    Folder(&'static Path),
+}
+struct MediaDef {
+    kind: MediaKind,
+    source: MediaSource,
+    descr: Option<String>,
+}
 #[derive(Default)]
 pub struct GodfishBotBuilder {
     txt_cmds: BTreeMap<&'static str, RandTextDef>,
     sounds: BTreeMap<&'static str, SoundDef>,
     images: BTreeMap<&'static str, &'static Path>,
     img_cmds: BTreeMap<&'static str, RandImgDef>,
-    other_cmds: BTreeMap<&'static str, CmdDef>,
+    media: BTreeMap<&'static str, MediaDef>,
+    other_cmds: BTreeMap<String, CmdDef>,
+    image_limits: Option<ImageLimits>,
 }
 #[derive(Debug, Clone)]
 struct CmdDef {
@@ -116,6 +168,18 @@ impl GodfishBotBuilder {
         );
         self
     }
+    /// Documents the commands defined in the API config in the help message.
+    pub fn api_commands(mut self, commands: &ApiCommands) -> Self {
+        for (cmd, def) in commands {
+            self.other_cmds.insert(cmd.clone(), CmdDef::new(&def.descr));
+        }
+        self
+    }
+    /// Normalise photos before they're first uploaded (if `limits` is set).
+    pub fn preprocess_images(mut self, limits: Option<ImageLimits>) -> Self {
+        self.image_limits = limits;
+        self
+    }
     /// Used to document other commands in the help message.
     pub fn other(
         mut self,
@@ -124,7 +188,7 @@ impl GodfishBotBuilder {
         usage: Option<&'static str>,
     ) -> Self {
         self.other_cmds.insert(
-            cmd,
+            cmd.to_string(),
             CmdDef {
                 usage: usage.map(Into::into),
                 descr: descr.into(),
@@ -132,7 +196,17 @@ impl GodfishBotBuilder {
         );
         self
     }
-    fn build_help(&self) -> (String, HashMap<String, CmdDef>) {
+    /// Every `image` and everything in the `rand_img` folders.
+    pub async fn image_paths(&self) -> Result<Vec<PathBuf>> {
+        let base = Path::new("res/images/");
+        let mut paths: Vec<PathBuf> = self.images.values().map(|img| base.join(img)).collect();
+        let base = Path::new("res/");
+        for def in self.img_cmds.values() {
+            paths.extend(list_folder(&base.join(def.folder)).await?);
+        }
+        Ok(paths)
+    }
+    fn build_help(&self, templates: &Templates) -> (String, HashMap<String, CmdDef>) {
         let mut help = format!(
             "GodfishBot v{}\nAvailable commands:\n\n",
             env!("CARGO_PKG_VERSION")
@@ -169,16 +243,48 @@ impl GodfishBotBuilder {
         }
         help.push_str("\nImage commands:\n");
         for (&cmd, _) in self.images.iter() {
-            help.push_str(&("/".to_string() + cmd + "\n"));
-            cmd_helps.insert(cmd.to_string(), CmdDef::new("Get a specific image"));
+            if templates.contains_key(cmd) {
+                help.push_str(&("/".to_string() + cmd + " - " + OVERLAY_HINT + "\n"));
+                cmd_helps.insert(
+                    cmd.to_string(),
+                    CmdDef::new("Get a specific image. ".to_string() + OVERLAY_HINT),
+                );
+            } else {
+                help.push_str(&("/".to_string() + cmd + "\n"));
+                cmd_helps.insert(cmd.to_string(), CmdDef::new("Get a specific image"));
+            }
         }
         help.push_str("\nRandom image commands:\n");
         for (&cmd, def) in self.img_cmds.iter() {
-            help.push_str(&("/".to_string() + cmd + " - " + &def.descr + "\n"));
-            cmd_helps.insert(cmd.to_string(), CmdDef::new(&def.descr));
+            let descr = if templates.contains_key(cmd) {
+                def.descr.clone() + ". " + OVERLAY_HINT
+            } else {
+                def.descr.clone()
+            };
+            let usage = format!("/{} [count (2-{}) | #index]", cmd, MAX_ALBUM_SIZE);
+            help.push_str(&(usage.clone() + " - " + &descr + "\n"));
+            cmd_helps.insert(
+                cmd.to_string(),
+                CmdDef {
+                    usage: Some(usage),
+                    descr,
+                },
+            );
+        }
+        if !self.media.is_empty() {
+            help.push_str("\nMedia commands:\n");
+        }
+        for (&cmd, def) in self.media.iter() {
+            let random = matches!(def.source, MediaSource::Folder(_));
+            let descr = def
+                .descr
+                .as_deref()
+                .unwrap_or_else(|| def.kind.default_descr(random));
+            help.push_str(&("/".to_string() + cmd + " - " + descr + "\n"));
+            cmd_helps.insert(cmd.to_string(), CmdDef::new(descr));
         }
         help.push_str("\nOther commands:\n");
-        for (&cmd, def) in self.other_cmds.iter() {
+        for (cmd, def) in self.other_cmds.iter() {
             let usage = def.usage.clone().unwrap_or_else(|| "/".to_string() + cmd);
             help.push_str(&(usage.to_string() + " - " + &def.descr + "\n"));
             cmd_helps.insert(cmd.to_string(), def.clone());
@@ -187,8 +293,19 @@ impl GodfishBotBuilder {
     }
     pub async fn build(mut self, bot: tbot::Bot) -> Result<EventLoop> {
         // 1. build help messsage
+        info!("Loading image templates...");
+        // Images still work without their overlays
+        let templates = overlay::load_templates(Path::new("res/images/templates.json"))
+            .await
+            .unwrap_or_else(|error| {
+                error!(
+                    ?error,
+                    "Error loading image templates, overlays are disabled"
+                );
+                Templates::new()
+            });
         info!("Generating help message...");
-        let (help_msg, cmd_helps) = self.build_help();
+        let (help_msg, cmd_helps) = self.build_help(&templates);
         let help_msg = Arc::new(help_msg);
         // 2. make basic event loop, fetch username, register help command
         let mut bot = bot.event_loop();
@@ -259,19 +376,37 @@ impl GodfishBotBuilder {
             });
         }
         // 4. image commands
+        let processed = if let Some(limits) = self.image_limits {
+            info!(?limits, "Preprocessing images...");
+            preprocess_images(self.image_paths().await?, limits).await
+        } else {
+            HashMap::new()
+        };
+        let processed = Arc::new(processed);
         info!("Registering simple image commands...");
         let mut bot = bot.into_stateful(Mutex::new(HashMap::<PathBuf, file::Id>::new()));
         let base = Path::new("res/images/");
         for (cmd, img) in mem::take(&mut self.images) {
             let path: Arc<PathBuf> = Arc::new([base, img].iter().collect());
+            let template = templates.get(cmd).cloned().map(Arc::new);
+            let processed = processed.clone();
             bot.command(cmd, move |ctx, state| {
                 let path = path.clone();
+                let template = template.clone();
+                let processed = processed.clone();
                 let reply_to_id = if let Some(Msg { id, .. }) = &ctx.reply_to {
                     *id
                 } else {
                     ctx.message_id
                 };
+                let target = reply_target(&ctx);
                 async move {
+                    if let (Some(template), Some(target)) = (template, target) {
+                        overlay::send_overlay(&ctx, &path, &template, target, reply_to_id)
+                            .await
+                            .unwrap_or_else(|error| error!(?error, ?path, "error sending overlay"));
+                        return;
+                    }
                     if let Some(id) = state.lock().await.get(&*path).cloned() {
                         ctx.send_photo(Photo::with_id(id))
                             .in_reply_to(reply_to_id)
@@ -280,7 +415,7 @@ impl GodfishBotBuilder {
                             .log_err_msg("error sending image");
                         return;
                     }
-                    let bytes = match fs::read(&*path).await {
+                    let bytes = match load_photo(&processed, &path).await {
                         Ok(x) => x,
                         Err(error) => {
                             error!(?error, ?path, "error loading file");
@@ -316,11 +451,7 @@ impl GodfishBotBuilder {
         let base = Path::new("res/");
         for (cmd, def) in mem::take(&mut self.img_cmds) {
             let folder: PathBuf = [base, def.folder].iter().collect();
-            let mut stream = fs::read_dir(&folder).await?;
-            let mut paths = Vec::new();
-            while let Some(entry) = stream.next_entry().await? {
-                paths.push(entry.path());
-            }
+            let paths = list_folder(&folder).await?;
             if paths.is_empty() {
                 error!(
                     command = ?cmd,
@@ -329,11 +460,34 @@ impl GodfishBotBuilder {
                 );
                 continue;
             }
-            paths.shrink_to_fit();
-            let paths = paths;
+            let template = templates.get(cmd).cloned().map(Arc::new);
+            let processed = processed.clone();
             bot.command(cmd, move |ctx, state| {
-                let path = paths.choose(&mut thread_rng()).cloned().unwrap();
+                let selection = select_images(&paths, &ctx.text.value);
+                let template = template.clone();
+                let processed = processed.clone();
+                let target = reply_target(&ctx)
+                    .zip(ctx.reply_to.as_ref())
+                    .map(|(target, msg)| (target, msg.id));
                 async move {
+                    let path = match selection {
+                        ImgSelection::Single(path) => path,
+                        ImgSelection::Album(paths) => {
+                            send_album(&ctx, &state, &processed, paths).await;
+                            return;
+                        }
+                        ImgSelection::OutOfRange(count) => {
+                            let msg = format!("Pick an image between #0 and #{}.", count - 1);
+                            ctx.send_message_in_reply(msg).call().await.log_err();
+                            return;
+                        }
+                    };
+                    if let (Some(template), Some((target, reply_to_id))) = (template, target) {
+                        overlay::send_overlay(&ctx, &path, &template, target, reply_to_id)
+                            .await
+                            .unwrap_or_else(|error| error!(?error, ?path, "error sending overlay"));
+                        return;
+                    }
                     if let Some(id) = state.lock().await.get(&path).cloned() {
                         ctx.send_photo(Photo::with_id(id))
                             .call()
@@ -341,7 +495,7 @@ impl GodfishBotBuilder {
                             .log_err_msg("error sending image");
                         return;
                     }
-                    let bytes = match fs::read(&path).await {
+                    let bytes = match load_photo(&processed, &path).await {
                         Ok(x) => x,
                         Err(error) => {
                             error!(?error, "error loading file");
@@ -365,6 +519,28 @@ impl GodfishBotBuilder {
                 }
             });
         }
+        info!("Registering media commands...");
+        for (cmd, MediaDef { kind, source, .. }) in mem::take(&mut self.media) {
+            let paths = match source {
+                MediaSource::File(file) => vec![[kind.base_dir(), file].iter().collect()],
+                MediaSource::Folder(folder) => list_folder(&kind.base_dir().join(folder)).await?,
+            };
+            if paths.is_empty() {
+                error!(command = ?cmd, ?kind, "ignoring media command: no files found");
+                continue;
+            }
+            bot.command(cmd, move |ctx, state| {
+                let path = paths.choose(&mut thread_rng()).cloned().unwrap();
+                let reply_to_id = if let Some(Msg { id, .. }) = &ctx.reply_to {
+                    *id
+                } else {
+                    ctx.message_id
+                };
+                async move {
+                    send_media(&ctx, &state, kind, path, reply_to_id).await;
+                }
+            });
+        }
         // 5. Sound commands
         info!("Registering sound commands...");
         let base = Path::new("res/sound/");
@@ -412,8 +588,274 @@ impl GodfishBotBuilder {
     }
 }
 
+// There are no media files in res/ yet, so only the tests use these.
+#[allow(dead_code)]
+impl GodfishBotBuilder {
+    fn media(
+        mut self,
+        cmd: &'static str,
+        kind: MediaKind,
+        source: MediaSource,
+        descr: Option<String>,
+    ) -> Self {
+        self.media.insert(
+            cmd,
+            MediaDef {
+                kind,
+                source,
+                descr,
+            },
+        );
+        self
+    }
+    pub fn animation(self, cmd: &'static str, file: &'static str) -> Self {
+        let source = MediaSource::File(Path::new(file));
+        self.media(cmd, MediaKind::Animation, source, None)
+    }
+    pub fn rand_animation(
+        self,
+        cmd: &'static str,
+        descr: impl Into<String>,
+        folder: &'static str,
+    ) -> Self {
+        let source = MediaSource::Folder(Path::new(folder));
+        self.media(cmd, MediaKind::Animation, source, Some(descr.into()))
+    }
+    pub fn video(self, cmd: &'static str, file: &'static str) -> Self {
+        let source = MediaSource::File(Path::new(file));
+        self.media(cmd, MediaKind::Video, source, None)
+    }
+    pub fn rand_video(
+        self,
+        cmd: &'static str,
+        descr: impl Into<String>,
+        folder: &'static str,
+    ) -> Self {
+        let source = MediaSource::Folder(Path::new(folder));
+        self.media(cmd, MediaKind::Video, source, Some(descr.into()))
+    }
+    pub fn video_note(self, cmd: &'static str, file: &'static str) -> Self {
+        let source = MediaSource::File(Path::new(file));
+        self.media(cmd, MediaKind::VideoNote, source, None)
+    }
+    pub fn rand_video_note(
+        self,
+        cmd: &'static str,
+        descr: impl Into<String>,
+        folder: &'static str,
+    ) -> Self {
+        let source = MediaSource::Folder(Path::new(folder));
+        self.media(cmd, MediaKind::VideoNote, source, Some(descr.into()))
+    }
+    pub fn sticker(self, cmd: &'static str, file: &'static str) -> Self {
+        let source = MediaSource::File(Path::new(file));
+        self.media(cmd, MediaKind::Sticker, source, None)
+    }
+    pub fn rand_sticker(
+        self,
+        cmd: &'static str,
+        descr: impl Into<String>,
+        folder: &'static str,
+    ) -> Self {
+        let source = MediaSource::Folder(Path::new(folder));
+        self.media(cmd, MediaKind::Sticker, source, Some(descr.into()))
+    }
+    pub fn document(self, cmd: &'static str, file: &'static str) -> Self {
+        let source = MediaSource::File(Path::new(file));
+        self.media(cmd, MediaKind::Document, source, None)
+    }
+    pub fn rand_document(
+        self,
+        cmd: &'static str,
+        descr: impl Into<String>,
+        folder: &'static str,
+    ) -> Self {
+        let source = MediaSource::Folder(Path::new(folder));
+        self.media(cmd, MediaKind::Document, source, Some(descr.into()))
+    }
+}
+
+/// Lists a folder's files, sorted by length first so `star2` comes before
+/// `star10`; this keeps indices stable and in line with the file names.
+async fn list_folder(folder: &Path) -> Result<Vec<PathBuf>> {
+    let mut stream = fs::read_dir(folder)
+        .await
+        .wrap_err_with(|| format!("Error reading {:?}", folder))?;
+    let mut paths = Vec::new();
+    while let Some(entry) = stream.next_entry().await? {
+        paths.push(entry.path());
+    }
+    paths.sort_by(|a, b| {
+        let (a, b) = (a.as_os_str(), b.as_os_str());
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    });
+    paths.shrink_to_fit();
+    Ok(paths)
+}
+
+async fn send_media(
+    ctx: &Command,
+    id_map: &Mutex<HashMap<PathBuf, file::Id>>,
+    kind: MediaKind,
+    path: PathBuf,
+    reply_to: message::Id,
+) {
+    let cached = id_map.lock().await.get(&path).cloned();
+    let bytes = if cached.is_some() {
+        Vec::new()
+    } else {
+        match fs::read(&path).await {
+            Ok(x) => x,
+            Err(error) => {
+                error!(?error, ?path, "error loading file");
+                return;
+            }
+        }
+    };
+    macro_rules! send {
+        ($method:ident, $input:expr) => {
+            ctx.$method($input).in_reply_to(reply_to).call().await
+        };
+    }
+    let is_cached = cached.is_some();
+    let result = match (kind, cached) {
+        (MediaKind::Animation, Some(id)) => send!(send_animation, Animation::with_id(id)),
+        (MediaKind::Animation, None) => send!(send_animation, Animation::with_bytes(bytes)),
+        (MediaKind::Video, Some(id)) => send!(send_video, Video::with_id(id)),
+        (MediaKind::Video, None) => send!(send_video, Video::with_bytes(bytes)),
+        (MediaKind::VideoNote, Some(id)) => send!(send_video_note, VideoNote::with_id(id)),
+        (MediaKind::VideoNote, None) => send!(send_video_note, VideoNote::with_bytes(bytes)),
+        (MediaKind::Sticker, Some(id)) => send!(send_sticker, Sticker::with_id(id)),
+        (MediaKind::Sticker, None) => send!(send_sticker, Sticker::with_bytes(bytes)),
+        (MediaKind::Document, Some(id)) => send!(send_document, Document::with_id(id)),
+        (MediaKind::Document, None) => {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            send!(send_document, Document::with_bytes(&name, bytes))
+        }
+    };
+    match result {
+        Ok(_) if is_cached => (),
+        Ok(msg) => match media_file_id(msg.kind) {
+            Some(id) => {
+                id_map.lock().await.insert(path, id);
+            }
+            None => error!(?path, ?kind, "Mysteriously didn't get a file id"),
+        },
+        Err(error) => error!(?error, ?path, "error sending file"),
+    }
+}
+
+fn media_file_id(kind: Kind) -> Option<file::Id> {
+    match kind {
+        Kind::Animation { animation, .. } => Some(animation.file_id),
+        // Telegram turns animations with audio into videos
+        Kind::Video { video, .. } => Some(video.file_id),
+        Kind::VideoNote(video_note) => Some(video_note.file_id),
+        Kind::Sticker(sticker) => Some(sticker.file_id),
+        Kind::Document { document, .. } => Some(document.file_id),
+        _ => None,
+    }
+}
+
+/// Telegram doesn't allow more than 10 items in a media group.
+const MAX_ALBUM_SIZE: usize = 10;
+
+enum ImgSelection {
+    Single(PathBuf),
+    Album(Vec<PathBuf>),
+    OutOfRange(usize),
+}
+
+/// Interprets the argument of a random image command: a number up to
+/// [`MAX_ALBUM_SIZE`] asks for that many distinct images (as many as there
+/// are), `#n` (or any larger number) picks a specific one and anything else
+/// gets a random image.
+fn select_images(paths: &[PathBuf], arg: &str) -> ImgSelection {
+    let arg = arg.trim();
+    let index = arg.strip_prefix('#').unwrap_or(arg);
+    match index.parse::<usize>() {
+        // Telegram rejects albums with a single item
+        Ok(2..=MAX_ALBUM_SIZE) if !arg.starts_with('#') && paths.len() < 2 => {
+            ImgSelection::Single(paths.choose(&mut thread_rng()).cloned().unwrap())
+        }
+        Ok(count @ 2..=MAX_ALBUM_SIZE) if !arg.starts_with('#') => ImgSelection::Album(
+            paths
+                .choose_multiple(&mut thread_rng(), count.min(paths.len()))
+                .cloned()
+                .collect(),
+        ),
+        Ok(index) => paths
+            .get(index)
+            .cloned()
+            .map(ImgSelection::Single)
+            .unwrap_or(ImgSelection::OutOfRange(paths.len())),
+        Err(_) => ImgSelection::Single(paths.choose(&mut thread_rng()).cloned().unwrap()),
+    }
+}
+
+/// Reads a photo from disk unless it has been preprocessed.
+async fn load_photo(processed: &HashMap<PathBuf, Vec<u8>>, path: &Path) -> io::Result<Vec<u8>> {
+    match processed.get(path) {
+        Some(bytes) => Ok(bytes.clone()),
+        None => fs::read(path).await,
+    }
+}
+
+async fn send_album(
+    ctx: &Command,
+    id_map: &Mutex<HashMap<PathBuf, file::Id>>,
+    processed: &HashMap<PathBuf, Vec<u8>>,
+    paths: Vec<PathBuf>,
+) {
+    let mut media = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let cached = id_map.lock().await.get(path).cloned();
+        let photo = if let Some(id) = cached {
+            Photo::with_id(id)
+        } else {
+            match load_photo(processed, path).await {
+                Ok(bytes) => Photo::with_bytes(bytes),
+                Err(error) => {
+                    error!(?error, ?path, "error loading file");
+                    return;
+                }
+            }
+        };
+        media.push(GroupMedia::from(photo));
+    }
+    match ctx.send_media_group(media).call().await {
+        Ok(messages) => {
+            let mut id_map = id_map.lock().await;
+            for (path, msg) in paths.into_iter().zip(messages) {
+                match msg.kind {
+                    Kind::Photo { photo, .. } => {
+                        if let Some(photo) = photo.into_iter().next() {
+                            id_map.insert(path, photo.file_id);
+                        } else {
+                            error!(?path, "Mysteriously didn't get a file id");
+                        }
+                    }
+                    _ => error!(?path, "non-photo in media group response"),
+                }
+            }
+        }
+        Err(error) => error!(?error, "error sending media group"),
+    }
+}
+
+const OVERLAY_HINT: &str = "Reply to someone to put them into the picture";
+
+/// The user whose message the command replied to, if any.
+fn reply_target(ctx: &Command) -> Option<user::Id> {
+    ctx.reply_to
+        .as_ref()
+        .and_then(|msg| msg.from.clone())
+        .and_then(|from| from.user())
+        .map(|user| user.id)
+}
+
 #[tracing::instrument]
-async fn load_file_lines(path: PathBuf) -> Result<Vec<String>> {
+pub async fn load_file_lines(path: PathBuf) -> Result<Vec<String>> {
     Ok(fs::read_to_string(path)
         .await?
         .lines()
@@ -444,3 +886,253 @@ pub fn random_sentence_at(options: &[String], username: &str, target: &str) -> S
         .replace("{0}", username)
         .replace("{1}", target)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::bot_api::FakeBotApi;
+
+    /// A media fixture; absolute, so it doesn't depend on the kind's base dir.
+    macro_rules! media {
+        ($path:literal) => {
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/media/", $path)
+        };
+    }
+
+    async fn start() -> FakeBotApi {
+        let tg = FakeBotApi::start().await;
+        let event_loop = GodfishBotBuilder::new()
+            .rand_text("hug", "/hug <target>", "Hug someone", "hugs.txt", None)
+            .image("bully", "bully.jpg")
+            .image("tease", "tease.jpg")
+            .audio("okay", &["okay.mp3"], None)
+            .animation("heart", media!("heart.gif"))
+            .rand_animation("hearts", "Get a random heart", media!("hearts/"))
+            .video("clip", media!("clip.mp4"))
+            .video_note("round", media!("round.mp4"))
+            .sticker("star", media!("star.webp"))
+            .document("notes", media!("notes.txt"))
+            .build(tg.bot())
+            .await
+            .unwrap();
+        tg.run(event_loop);
+        tg
+    }
+
+    #[tokio::test]
+    async fn help() {
+        let tg = start().await;
+        let alice = tg.user("Alice");
+
+        alice.sends("/help").send();
+        let help = tg.expect("sendMessage").await.text();
+        assert!(help.starts_with("GodfishBot v"), "{}", help);
+        assert!(help.contains("\n/hug <target> - Hug someone\n"), "{}", help);
+        assert!(help.contains("\n/bully - "), "{}", help);
+
+        alice.sends("/help hug").send();
+        let call = tg.expect("sendMessage").await;
+        assert_eq!(call.text(), "Usage: /hug <target>\n\nHug someone");
+
+        alice.sends("/help nope").send();
+        assert_eq!(tg.expect("sendMessage").await.text(), "Command not found!");
+    }
+
+    #[tokio::test]
+    async fn text_command_mentions_sender_and_target() {
+        let tg = start().await;
+        let group = tg.group("Testers");
+
+        tg.user("Alice").sends("/hug Bob").in_chat(&group).send();
+        let call = tg.expect("sendMessage").await;
+        assert_eq!(call.param("chat_id"), Some(group.id.to_string()));
+        let text = call.text();
+        assert!(text.contains("Alice") && text.contains("Bob"), "{}", text);
+
+        tg.user("Alice").sends("/hug").in_chat(&group).send();
+        assert_eq!(tg.expect("sendMessage").await.text(), "/hug <target>");
+    }
+
+    #[tokio::test]
+    async fn uploaded_images_are_reused_by_file_id() {
+        let tg = start().await;
+        let alice = tg.user("Alice");
+
+        alice.sends("/tease").send();
+        let first = tg.expect("sendPhoto").await;
+        assert_eq!(
+            first.files["photo"],
+            fs::read("res/images/tease.jpg").await.unwrap()
+        );
+
+        alice.sends("/tease").send();
+        let second = tg.expect("sendPhoto").await;
+        assert!(second.files.is_empty());
+        assert_eq!(second.param("photo"), Some(first.file_id()));
+    }
+
+    #[tokio::test]
+    async fn image_commands_reply_to_the_replied_message() {
+        let tg = start().await;
+        let group = tg.group("Testers");
+        let bob = tg.user("Bob");
+        let hello = bob.sends("hello").in_chat(&group).send();
+
+        let command = tg
+            .user("Alice")
+            .sends("/tease")
+            .in_chat(&group)
+            .replying_to(&hello)
+            .send();
+        let call = tg.expect("sendPhoto").await;
+        assert_eq!(
+            call.param("reply_to_message_id"),
+            Some(hello.id().to_string())
+        );
+        assert_ne!(hello.id(), command.id());
+    }
+
+    #[tokio::test]
+    async fn overlay_needs_a_profile_picture() {
+        let tg = start().await;
+        let group = tg.group("Testers");
+        let bob = tg.user("Bob");
+        let hello = bob.sends("hello").in_chat(&group).send();
+
+        tg.user("Alice")
+            .sends("/bully")
+            .in_chat(&group)
+            .replying_to(&hello)
+            .send();
+        let call = tg.expect("getUserProfilePhotos").await;
+        assert_eq!(call.param("user_id"), Some(bob.id.to_string()));
+        let call = tg.expect("sendMessage").await;
+        assert_eq!(call.text(), "I can't see their profile picture :(");
+    }
+
+    #[tokio::test]
+    async fn overlay_puts_the_profile_picture_into_the_image() {
+        let tg = start().await;
+        let group = tg.group("Testers");
+        let bob = tg.user("Bob");
+        let mut avatar = Vec::new();
+        image::DynamicImage::new_rgb8(64, 64)
+            .write_to(
+                &mut io::Cursor::new(&mut avatar),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        tg.profile_photo(&bob, avatar);
+        let hello = bob.sends("hello").in_chat(&group).send();
+
+        tg.user("Alice")
+            .sends("/bully")
+            .in_chat(&group)
+            .replying_to(&hello)
+            .send();
+        let call = tg.expect("getUserProfilePhotos").await;
+        assert_eq!(call.param("user_id"), Some(bob.id.to_string()));
+        let call = tg.expect("getFile").await;
+        assert_eq!(call.param("file_id"), Some(format!("avatar-{}", bob.id)));
+        let call = tg.expect("sendPhoto").await;
+        assert_eq!(
+            call.param("reply_to_message_id"),
+            Some(hello.id().to_string())
+        );
+        let photo = &call.files["photo"];
+        assert!(photo.starts_with(b"\xFF\xD8"), "not a JPEG");
+        let bully = fs::read("res/images/bully.jpg").await.unwrap();
+        assert_ne!(photo, &bully);
+    }
+
+    #[tokio::test]
+    async fn sounds_are_uploaded_as_voice() {
+        let tg = start().await;
+
+        tg.user("Alice").sends("/okay").send();
+        let call = tg.expect("sendVoice").await;
+        assert_eq!(
+            call.files["voice"],
+            fs::read("res/sound/okay.mp3").await.unwrap()
+        );
+
+        tg.user("Alice").sends("/nonexistent").send();
+        tg.expect_nothing().await;
+    }
+
+    #[test]
+    fn albums_are_limited_to_the_available_images() {
+        let paths: Vec<PathBuf> = vec!["a.jpg".into(), "b.jpg".into(), "c.jpg".into()];
+        match select_images(&paths, "5") {
+            ImgSelection::Album(album) => assert_eq!(album.len(), 3),
+            _ => panic!("expected an album"),
+        }
+        match select_images(&paths[..1], "2") {
+            ImgSelection::Single(path) => assert_eq!(path, paths[0]),
+            _ => panic!("expected a single image"),
+        }
+    }
+
+    /// Sends `command` twice, checking that `file` is uploaded as `field` of
+    /// a `method` call the first time and sent by its file ID the second.
+    async fn assert_uploaded_once(
+        tg: &FakeBotApi,
+        command: &str,
+        method: &str,
+        field: &str,
+        file: &str,
+    ) {
+        let alice = tg.user("Alice");
+        alice.sends(command).send();
+        let first = tg.expect(method).await;
+        assert_eq!(first.files[field], fs::read(file).await.unwrap());
+        alice.sends(command).send();
+        let second = tg.expect(method).await;
+        assert!(second.files.is_empty(), "{:?}", second);
+        assert_eq!(second.param(field), Some(first.file_id()));
+    }
+
+    #[tokio::test]
+    async fn animations_are_uploaded_once() {
+        let tg = start().await;
+        let heart = media!("heart.gif");
+        assert_uploaded_once(&tg, "/heart", "sendAnimation", "animation", heart).await;
+
+        tg.user("Alice").sends("/hearts").send();
+        let call = tg.expect("sendAnimation").await;
+        let upload = &call.files["animation"];
+        let mut hearts = Vec::new();
+        for path in list_folder(Path::new(media!("hearts/"))).await.unwrap() {
+            hearts.push(fs::read(path).await.unwrap());
+        }
+        assert_eq!(hearts.len(), 3);
+        assert!(hearts.contains(upload));
+    }
+
+    #[tokio::test]
+    async fn videos_are_uploaded_once() {
+        let tg = start().await;
+        assert_uploaded_once(&tg, "/clip", "sendVideo", "video", media!("clip.mp4")).await;
+    }
+
+    #[tokio::test]
+    async fn video_notes_are_uploaded_once() {
+        let tg = start().await;
+        let round = media!("round.mp4");
+        assert_uploaded_once(&tg, "/round", "sendVideoNote", "video_note", round).await;
+    }
+
+    #[tokio::test]
+    async fn stickers_are_uploaded_once() {
+        let tg = start().await;
+        let star = media!("star.webp");
+        assert_uploaded_once(&tg, "/star", "sendSticker", "sticker", star).await;
+    }
+
+    #[tokio::test]
+    async fn documents_are_uploaded_once() {
+        let tg = start().await;
+        let notes = media!("notes.txt");
+        assert_uploaded_once(&tg, "/notes", "sendDocument", "document", notes).await;
+    }
+}
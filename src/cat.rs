@@ -0,0 +1,218 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{
+    animal::{refresh_variants, AnimalProvider, Media},
+    error::ApiError,
+    http::Http,
+};
+
+/// Random cats from TheCatAPI (or something compatible).
+#[derive(Clone)]
+pub struct TheCatApi {
+    http: Http,
+    api_url: String,
+    /// Sent as `x-api-key`; TheCatAPI works without one, but with limits
+    api_key: Option<String>,
+    breeds: Arc<RwLock<CatBreeds>>,
+}
+
+/// The breed list, replaced as a whole when it is refreshed.
+#[derive(Default)]
+struct CatBreeds {
+    names: Arc<BTreeSet<String>>,
+    /// Lowercase breed names to their IDs
+    ids: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Breed {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CatImage {
+    url: String,
+}
+
+impl TheCatApi {
+    /// Loads the breed list; without it, cats can't be filtered by breed
+    /// until [`refresh_breeds`](Self::refresh_breeds) gets it.
+    pub async fn new(http: Http, api_url: String, api_key: Option<String>) -> Self {
+        let cats = TheCatApi {
+            http,
+            api_url,
+            api_key,
+            breeds: Arc::default(),
+        };
+        if let Err(error) = cats.load_breeds().await {
+            error!(?error, "Error loading cat breeds, using empty breed list");
+        }
+        cats
+    }
+
+    /// Keeps the breed list up to date, retrying with backoff when fetching
+    /// fails.
+    pub async fn refresh_breeds(self, interval: Duration) {
+        let known = !self.variants().is_empty();
+        let cats = &self;
+        refresh_variants("cat", interval, known, move || cats.load_breeds()).await
+    }
+
+    /// Fetches the breed list and replaces the current one, returning how
+    /// many breeds there are.
+    async fn load_breeds(&self) -> Result<usize, ApiError> {
+        let url = format!("{}/breeds", self.api_url);
+        let breeds: Vec<Breed> = self.get(&url).await?;
+        let ids: HashMap<_, _> = breeds
+            .into_iter()
+            .map(|breed| (breed.name.to_lowercase(), breed.id))
+            .collect();
+        let names = Arc::new(ids.keys().cloned().collect::<BTreeSet<_>>());
+        let count = names.len();
+        *self.breeds.write().unwrap() = CatBreeds { names, ids };
+        Ok(count)
+    }
+
+    async fn search(&self, breed_id: Option<&str>) -> Result<Option<Media>, ApiError> {
+        let url = match breed_id {
+            Some(id) => format!("{}/images/search?breed_ids={}", self.api_url, id),
+            None => format!("{}/images/search", self.api_url),
+        };
+        let images: Vec<CatImage> = self.get(&url).await?;
+        Ok(images
+            .into_iter()
+            .next()
+            .map(|image| Media::from_url(image.url)))
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, ApiError> {
+        let mut request = self.http.get(url);
+        if let Some(key) = &self.api_key {
+            request = request.header("x-api-key", key);
+        }
+        Ok(request.json().await?.body)
+    }
+}
+
+#[async_trait]
+impl AnimalProvider for TheCatApi {
+    async fn fetch_random(&self) -> Result<Media, ApiError> {
+        self.search(None)
+            .await?
+            .ok_or_else(|| ApiError::Response(eyre!("cat API: no cat found")))
+    }
+    fn has_variants(&self) -> bool {
+        true
+    }
+    fn variants(&self) -> Arc<BTreeSet<String>> {
+        self.breeds.read().unwrap().names.clone()
+    }
+    async fn fetch_variant(&self, breed: &str) -> Result<Option<Media>, ApiError> {
+        let id = match self.breeds.read().unwrap().ids.get(breed) {
+            Some(id) => id.clone(),
+            None => return Ok(None),
+        };
+        Ok(self.search(Some(&id)).await?.map(|media| Media {
+            caption: Some(breed.to_string()),
+            variant: Some(breed.to_string()),
+            ..media
+        }))
+    }
+    fn not_found(&self) -> &'static str {
+        "Breed not found!"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::{
+        animal::MediaKind,
+        test_util::{fixture, mount, test_http},
+    };
+
+    #[tokio::test]
+    async fn cats_by_breed() {
+        let server = MockServer::start().await;
+        mount(&server, "/breeds", 200, &fixture("thecatapi/breeds.json")).await;
+        Mock::given(method("GET"))
+            .and(path("/images/search"))
+            .and(query_param("breed_ids", "bsho"))
+            .and(header("x-api-key", "secret"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(fixture("thecatapi/search.json"), "application/json"),
+            )
+            .mount(&server)
+            .await;
+        let cats = TheCatApi::new(test_http(), server.uri(), Some("secret".into())).await;
+        assert!(cats.variants().contains("british shorthair"));
+        assert!(cats.variants().contains("maine coon"));
+
+        let media = cats
+            .fetch_variant("british shorthair")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(media.kind, MediaKind::Photo);
+        assert_eq!(
+            media.url,
+            "https://cdn2.thecatapi.com/images/MTY3ODIyMQ.jpg"
+        );
+        assert!(cats.fetch_variant("tabby").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn random_cat_gif() {
+        let server = MockServer::start().await;
+        mount(&server, "/breeds", 500, "{}").await;
+        mount(
+            &server,
+            "/images/search",
+            200,
+            &fixture("thecatapi/search_gif.json"),
+        )
+        .await;
+        let cats = TheCatApi::new(test_http(), server.uri(), None).await;
+        assert!(cats.variants().is_empty());
+        let media = cats.fetch_random().await.unwrap();
+        assert_eq!(media.kind, MediaKind::Animation);
+    }
+
+    #[tokio::test]
+    async fn breeds_can_be_loaded_later() {
+        let server = MockServer::start().await;
+        mount(&server, "/breeds", 500, "{}").await;
+        let cats = TheCatApi::new(test_http(), server.uri(), None).await;
+        assert!(cats.variants().is_empty());
+
+        server.reset().await;
+        mount(&server, "/breeds", 200, &fixture("thecatapi/breeds.json")).await;
+        assert_eq!(cats.load_breeds().await.unwrap(), 4);
+        assert!(cats.variants().contains("maine coon"));
+    }
+
+    #[tokio::test]
+    async fn no_cats() {
+        let server = MockServer::start().await;
+        mount(&server, "/images/search", 200, "[]").await;
+        let cats = TheCatApi::new(test_http(), server.uri(), None).await;
+        let result = cats.fetch_random().await;
+        assert!(matches!(result, Err(ApiError::Response(_))), "{:?}", result);
+    }
+}
@@ -0,0 +1,285 @@
+//! "Couple of the day": two recently active members of a chat, picked once a
+//! day and announced with their love test result.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{bail, Result, WrapErr};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use tbot::{
+    contexts::{methods::Message, Command},
+    types::{chat, parameters::Text, user},
+    EventLoop,
+};
+use tracing::error;
+
+use crate::{
+    api::render,
+    bot::load_file_lines,
+    love_test::test_love,
+    members::{Member, Members},
+    persist::{load_json, save_json},
+    ResultExt,
+};
+
+pub static USAGE: &str = "/couple [optout|optin] (only members who wrote something since the bot last started can be picked)";
+
+/// Who was active is only known from the messages the bot saw since it
+/// started ([`Members`] isn't saved), so right after a restart there may not
+/// be enough members to pick a couple.
+pub struct CoupleConfig {
+    /// Only members who were active this recently can be picked
+    pub active_within: Duration,
+    /// Where the members who opted out are kept
+    pub opt_outs_path: PathBuf,
+}
+impl CoupleConfig {
+    /// `COUPLE_ACTIVE_DAYS` (default 7) and `COUPLE_OPT_OUTS` (default
+    /// `data/couple_opt_outs.json`).
+    pub fn from_env() -> Result<Self> {
+        let active_days: u64 = match env::var("COUPLE_ACTIVE_DAYS") {
+            Ok(val) => val.parse().wrap_err("Invalid COUPLE_ACTIVE_DAYS")?,
+            Err(_) => 7,
+        };
+        let opt_outs_path =
+            env::var("COUPLE_OPT_OUTS").unwrap_or_else(|_| "data/couple_opt_outs.json".into());
+        Ok(CoupleConfig {
+            active_within: Duration::from_secs(active_days * 24 * 60 * 60),
+            opt_outs_path: opt_outs_path.into(),
+        })
+    }
+}
+
+/// Users who don't want to be picked, by chat ID.
+type OptOuts = BTreeMap<i64, BTreeSet<i64>>;
+
+#[derive(Clone)]
+struct Couple {
+    day: u64,
+    members: (Member, Member),
+    template: usize,
+}
+
+struct Couples {
+    members: Members,
+    config: CoupleConfig,
+    templates: Vec<String>,
+    opt_outs: tokio::sync::Mutex<OptOuts>,
+    today: Mutex<HashMap<chat::Id, Couple>>,
+}
+
+pub async fn register(bot: &mut EventLoop, members: Members, config: CoupleConfig) -> Result<()> {
+    let templates = load_file_lines("res/txt/couple.txt".into()).await?;
+    if templates.is_empty() {
+        bail!("No couple templates found");
+    }
+    let opt_outs = load_json(&config.opt_outs_path).await?;
+    let couples = Arc::new(Couples {
+        members,
+        config,
+        templates,
+        opt_outs: tokio::sync::Mutex::new(opt_outs),
+        today: Default::default(),
+    });
+    bot.command("couple", move |ctx| handler(ctx, couples.clone()));
+    Ok(())
+}
+
+async fn handler(ctx: Arc<Command>, couples: Arc<Couples>) {
+    let user = ctx.from.clone().and_then(|from| from.user());
+    if let Some(user) = &user {
+        couples.members.record(ctx.chat.id, user);
+    }
+    let reply = match (ctx.text.value.trim(), &user) {
+        ("", _) => {
+            let opt_outs = couples.opt_outs.lock().await;
+            couples.today(ctx.chat.id, &opt_outs).unwrap_or_else(|| {
+                "Not enough people have been active here lately to pick a couple.".into()
+            })
+        }
+        ("optout", Some(user)) => {
+            couples.set_opted_out(ctx.chat.id, user.id, true).await;
+            "You won't be picked as part of a couple any more. Changed your mind? /couple optin"
+                .into()
+        }
+        ("optin", Some(user)) => {
+            couples.set_opted_out(ctx.chat.id, user.id, false).await;
+            "You can be picked as part of a couple again.".into()
+        }
+        _ => USAGE.to_string(),
+    };
+    ctx.send_message(Text::with_html(reply))
+        .call()
+        .await
+        .log_err();
+}
+
+impl Couples {
+    /// Today's couple in the chat, picked on the first call of the day.
+    fn today(&self, chat: chat::Id, opt_outs: &OptOuts) -> Option<String> {
+        let day = current_day();
+        let mut today = self.today.lock().unwrap();
+        let couple = match today.get(&chat) {
+            Some(couple) if couple.day == day => couple.clone(),
+            _ => {
+                let couple = self.pick(chat, day, opt_outs)?;
+                today.insert(chat, couple.clone());
+                couple
+            }
+        };
+        let (a, b) = &couple.members;
+        let values = vec![
+            ("0", a.mention()),
+            ("1", b.mention()),
+            ("2", test_love(&a.first_name, &b.first_name)),
+        ];
+        Some(render(
+            &self.templates[couple.template],
+            &values.into_iter().collect(),
+        ))
+    }
+
+    /// Picks two of the active members, seeded by chat and day.
+    fn pick(&self, chat: chat::Id, day: u64, opt_outs: &OptOuts) -> Option<Couple> {
+        let since = SystemTime::now() - self.config.active_within;
+        let opted_out = opt_outs.get(&chat.0).cloned().unwrap_or_default();
+        let candidates: Vec<Member> = self
+            .members
+            .active_since(chat, since)
+            .into_iter()
+            .filter(|member| !opted_out.contains(&member.id.0))
+            .collect();
+        let seed = (chat.0 as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ day;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut pair = candidates.choose_multiple(&mut rng, 2).cloned();
+        Some(Couple {
+            day,
+            members: (pair.next()?, pair.next()?),
+            template: rng.gen_range(0..self.templates.len()),
+        })
+    }
+
+    async fn set_opted_out(&self, chat: chat::Id, user: user::Id, opted_out: bool) {
+        let mut opt_outs = self.opt_outs.lock().await;
+        let ids = opt_outs.entry(chat.0).or_default();
+        if opted_out {
+            ids.insert(user.0);
+            // Someone who opted out shouldn't stay today's couple either
+            let mut today = self.today.lock().unwrap();
+            if let Some(Couple {
+                members: (a, b), ..
+            }) = today.get(&chat)
+            {
+                if a.id == user || b.id == user {
+                    today.remove(&chat);
+                }
+            }
+        } else {
+            ids.remove(&user.0);
+        }
+        if let Err(error) = save_json(&self.config.opt_outs_path, &*opt_outs).await {
+            error!(?error, "error saving couple opt-outs");
+        }
+    }
+}
+
+/// Days since the Unix epoch (in UTC).
+fn current_day() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / (24 * 60 * 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{bot_api::FakeBotApi, TempPath};
+
+    #[tokio::test]
+    async fn couple_of_the_day() {
+        let path = TempPath::new("opt-outs");
+        let config = CoupleConfig {
+            active_within: Duration::from_secs(60 * 60),
+            opt_outs_path: path.to_path_buf(),
+        };
+        let tg = FakeBotApi::start().await;
+        let mut bot = tg.bot().event_loop();
+        register(&mut bot, Members::default(), config)
+            .await
+            .unwrap();
+        tg.run(bot);
+        let group = tg.group("Lovers");
+        let alice = tg.user("Alice");
+        let bob = tg.user("Bob");
+        let carol = tg.user("Carol");
+
+        alice.sends("/couple").in_chat(&group).send();
+        let text = tg.expect("sendMessage").await.text();
+        assert!(text.starts_with("Not enough people"), "{}", text);
+
+        bob.sends("/couple optin").in_chat(&group).send();
+        tg.expect("sendMessage").await;
+        carol.sends("/couple optout").in_chat(&group).send();
+        tg.expect("sendMessage").await;
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(saved, format!("{{\"{}\":[{}]}}", group.id, carol.id));
+
+        alice.sends("/couple").in_chat(&group).send();
+        let couple = tg.expect("sendMessage").await;
+        assert_eq!(couple.param("parse_mode").as_deref(), Some("HTML"));
+        let text = couple.text();
+        assert!(
+            text.contains(&format!("tg://user?id={}", alice.id)),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(&format!("tg://user?id={}", bob.id)),
+            "{}",
+            text
+        );
+        assert!(text.contains(&test_love("Alice", "Bob")), "{}", text);
+        bob.sends("/couple").in_chat(&group).send();
+        assert_eq!(tg.expect("sendMessage").await.text(), text);
+
+        bob.sends("/couple optout").in_chat(&group).send();
+        tg.expect("sendMessage").await;
+        alice.sends("/couple").in_chat(&group).send();
+        let text = tg.expect("sendMessage").await.text();
+        assert!(text.starts_with("Not enough people"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn names_arent_taken_for_placeholders() {
+        let path = TempPath::new("opt-outs-placeholders");
+        let config = CoupleConfig {
+            active_within: Duration::from_secs(60 * 60),
+            opt_outs_path: path.to_path_buf(),
+        };
+        let tg = FakeBotApi::start().await;
+        let mut bot = tg.bot().event_loop();
+        register(&mut bot, Members::default(), config)
+            .await
+            .unwrap();
+        tg.run(bot);
+        let group = tg.group("Lovers");
+        let alice = tg.user("Alice");
+        let sneaky = tg.user("{2}");
+
+        sneaky.sends("/couple optin").in_chat(&group).send();
+        tg.expect("sendMessage").await;
+        alice.sends("/couple").in_chat(&group).send();
+        let text = tg.expect("sendMessage").await.text();
+        assert!(
+            text.contains(&format!("tg://user?id={}\">{{2}}</a>", sneaky.id)),
+            "{}",
+            text
+        );
+    }
+}
@@ -1,77 +1,109 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
-    sync::Arc,
+    collections::{BTreeMap, BTreeSet},
+    env,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
-use color_eyre::{eyre::eyre, Result};
-use itertools::Itertools;
-use reqwest::{Client, StatusCode};
+use async_trait::async_trait;
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report, Result,
+};
+use reqwest::StatusCode;
 use serde_json::Value;
-use tbot::{
-    contexts::{methods::Message, Command},
-    types::{file, message::Kind, Message as Msg},
+use tokio::fs;
+use tracing::{error, info};
+
+use crate::{
+    animal::{refresh_variants, AnimalProvider, Media},
+    error::ApiError,
+    http::Http,
+    persist::save_json,
 };
-use tokio::sync::Mutex;
-use tracing::error;
-
-use crate::ResultExt;
-
-type State = Arc<(Client, BTreeSet<String>, Mutex<HashMap<String, file::Id>>)>;
-
-pub async fn doggo_handler(ctx: Arc<Command>, state: State) {
-    let (client, all_breeds, id_map) = &*state;
-    let queried_breed = (!ctx.text.value.is_empty()).then(|| ctx.text.value.clone());
-    match query_api(client, all_breeds, queried_breed).await {
-        Ok(QueryResult::Doggo { url }) => {
-            use tbot::types::input_file::Photo;
-            if let Some(id) = id_map.lock().await.get(&url).cloned() {
-                ctx.send_photo(Photo::with_id(id))
-                    .call()
-                    .await
-                    .log_err_msg("error sending image");
-                return;
-            }
-            match ctx.send_photo(Photo::with_url(&url)).call().await {
-                Ok(Msg {
-                    kind: Kind::Photo { photo, .. },
-                    ..
-                }) => {
-                    if let Some(photo) = photo.into_iter().next() {
-                        id_map.lock().await.insert(url, photo.file_id);
-                    } else {
-                        error!(?url, "Mysteriously didn't get a file id");
-                    }
-                }
-                Err(error) => error!(?error, "error sending doggo"),
-                _ => unreachable!("non-photo from SendPhoto"),
-            };
-        }
-        Ok(QueryResult::Error { msg }) => {
-            ctx.send_message_in_reply(msg).call().await.log_err();
-        }
-        Err(error) => {
-            error!(?error, "error fetching doggo");
-        }
-    };
+
+/// Random doggos from dog.ceo (or something compatible).
+pub struct DogCeo {
+    pub http: Http,
+    pub api_url: String,
+    pub breeds: Breeds,
+}
+
+#[async_trait]
+impl AnimalProvider for DogCeo {
+    async fn fetch_random(&self) -> Result<Media, ApiError> {
+        query_api(&self.http, &self.api_url, None)
+            .await?
+            .ok_or_else(|| ApiError::Response(eyre!("doggo API: no random doggo found")))
+    }
+    fn has_variants(&self) -> bool {
+        true
+    }
+    fn has_sub_variants(&self) -> bool {
+        true
+    }
+    fn variants(&self) -> Arc<BTreeSet<String>> {
+        self.breeds.get()
+    }
+    async fn fetch_variant(&self, breed: &str) -> Result<Option<Media>, ApiError> {
+        query_api(&self.http, &self.api_url, Some(breed)).await
+    }
+    fn not_found(&self) -> &'static str {
+        "Breed not found!"
+    }
+}
+
+/// The known breeds, swapped out whenever a refresh succeeds.
+#[derive(Clone, Default)]
+pub struct Breeds(Arc<RwLock<Arc<BTreeSet<String>>>>);
+impl Breeds {
+    pub fn new(breeds: BTreeSet<String>) -> Self {
+        Breeds(Arc::new(RwLock::new(Arc::new(breeds))))
+    }
+    pub fn get(&self) -> Arc<BTreeSet<String>> {
+        self.0.read().unwrap().clone()
+    }
+    fn set(&self, breeds: BTreeSet<String>) {
+        *self.0.write().unwrap() = Arc::new(breeds);
+    }
 }
-pub async fn breeds_handler(ctx: Arc<Command>, state: State) {
-    let all_breeds = state.1.iter().join("\n");
-    let text = format!("Available doggo breeds:\n\n{}", all_breeds);
-    ctx.send_message_in_reply(text).call().await.log_err();
+
+#[derive(Debug, Clone)]
+pub struct BreedsConfig {
+    /// How often the breed list is fetched again
+    pub refresh_interval: Duration,
+    /// Where the last successfully fetched list is kept
+    pub cache_path: PathBuf,
+}
+impl BreedsConfig {
+    /// `DOGGO_BREEDS_REFRESH_SECS` (default one day) and `DOGGO_BREEDS_CACHE`
+    /// (default `cache/breeds.json`).
+    pub fn from_env() -> Result<Self> {
+        let refresh_secs = match env::var("DOGGO_BREEDS_REFRESH_SECS") {
+            Ok(val) => val.parse().wrap_err("Invalid DOGGO_BREEDS_REFRESH_SECS")?,
+            Err(_) => 24 * 60 * 60,
+        };
+        if refresh_secs == 0 {
+            bail!("DOGGO_BREEDS_REFRESH_SECS must be at least 1");
+        }
+        let cache_path =
+            env::var("DOGGO_BREEDS_CACHE").unwrap_or_else(|_| "cache/breeds.json".into());
+        Ok(BreedsConfig {
+            refresh_interval: Duration::from_secs(refresh_secs),
+            cache_path: cache_path.into(),
+        })
+    }
 }
 
-pub async fn fetch_breeds(client: &Client) -> Result<BTreeSet<String>> {
+pub async fn fetch_breeds(http: &Http, api_url: &str) -> Result<BTreeSet<String>> {
     #[derive(Debug, serde::Deserialize)]
     struct BreedResponse {
         status: String,
         message: Value,
     }
-    let resp: BreedResponse = client
-        .get("https://dog.ceo/api/breeds/list/all")
-        .send()
-        .await?
-        .json()
-        .await?;
+    let url = format!("{}/breeds/list/all", api_url);
+    let resp: BreedResponse = http.get(&url).json().await?.body;
     if resp.status != "success" {
         let msg = if let Some(error) = resp.message.as_str() {
             eyre!("Error fetching breed list: {:?}", error)
@@ -97,60 +129,270 @@ pub async fn fetch_breeds(client: &Client) -> Result<BTreeSet<String>> {
         .collect())
 }
 
-enum QueryResult {
-    Doggo { url: String },
-    Error { msg: String },
+/// Fetches the breed list, falling back to the last one that could be
+/// fetched if the API is unreachable.
+pub async fn load_breeds(http: &Http, api_url: &str, cache_path: &Path) -> BTreeSet<String> {
+    match fetch_breeds(http, api_url).await {
+        Ok(breeds) => {
+            if let Err(error) = save_json(cache_path, &breeds).await {
+                error!(?error, "error saving breed list");
+            }
+            return breeds;
+        }
+        Err(error) => error!(?error, "Error loading doggo breeds"),
+    }
+    match read_breeds(cache_path).await {
+        Ok(breeds) => {
+            info!(path = ?cache_path, count = breeds.len(), "Using cached breed list");
+            breeds
+        }
+        Err(error) => {
+            error!(?error, "Using empty breed list");
+            Default::default()
+        }
+    }
+}
+
+/// Keeps `breeds` up to date, retrying with backoff when fetching fails.
+pub async fn refresh_breeds(http: Http, api_url: String, breeds: Breeds, config: BreedsConfig) {
+    let known = !breeds.get().is_empty();
+    let (http, api_url, breeds, cache_path) = (&http, &api_url, &breeds, &config.cache_path);
+    refresh_variants(
+        "doggo",
+        config.refresh_interval,
+        known,
+        move || async move {
+            let fetched = fetch_breeds(http, api_url).await?;
+            if let Err(error) = save_json(cache_path, &fetched).await {
+                error!(?error, "error saving breed list");
+            }
+            let count = fetched.len();
+            breeds.set(fetched);
+            Ok::<_, Report>(count)
+        },
+    )
+    .await
+}
+
+async fn read_breeds(path: &Path) -> Result<BTreeSet<String>> {
+    let json = fs::read(path)
+        .await
+        .wrap_err_with(|| format!("Error reading {:?}", path))?;
+    serde_json::from_slice(&json).wrap_err_with(|| format!("Error parsing {:?}", path))
+}
+
+/// Turns a breed name into its API path: "golden retriever" is the sub-breed
+/// "golden" of "retriever".
+fn breed_path(breed: &str) -> String {
+    match breed.rsplit_once(' ') {
+        Some((sub_breed, breed)) => format!("{}/{}", breed, sub_breed.replace(' ', "")),
+        None => breed.to_string(),
+    }
+}
+
+/// Finds the breed in an image URL like
+/// `https://images.dog.ceo/breeds/retriever-golden/n02099601_3004.jpg`.
+fn breed_from_url(url: &str) -> Option<String> {
+    let mut segments = url.split('/').skip_while(|segment| *segment != "breeds");
+    let breed = segments.nth(1).filter(|breed| !breed.is_empty())?;
+    Some(match breed.split_once('-') {
+        Some((breed, sub_breed)) => format!("{} {}", sub_breed, breed),
+        None => breed.to_string(),
+    })
 }
+
+/// Fetches a random doggo (of `breed`); `None` if the breed doesn't exist.
 async fn query_api(
-    client: &Client,
-    all_breeds: &BTreeSet<String>,
-    queried_breed: Option<String>,
-) -> Result<QueryResult> {
-    let url = if let Some(breed) = &queried_breed {
-        use std::borrow::Cow;
-        let breed = if breed.contains(' ') {
-            Cow::Owned(breed.rsplit(' ').collect::<Vec<_>>().join("/"))
-        } else {
-            Cow::Borrowed(breed)
-        };
-        format!(
-            "https://dog.ceo/api/breed/{}/images/random",
-            breed.to_ascii_lowercase()
-        )
+    http: &Http,
+    api_url: &str,
+    breed: Option<&str>,
+) -> Result<Option<Media>, ApiError> {
+    let url = if let Some(breed) = breed {
+        format!("{}/breed/{}/images/random", api_url, breed_path(breed))
     } else {
-        "https://dog.ceo/api/breeds/image/random".into()
+        format!("{}/breeds/image/random", api_url)
     };
     #[derive(Debug, serde::Deserialize)]
     struct DoggoQueryResult {
         message: String,
         status: String,
     }
-    let resp = client.get(&url).send().await?;
-    let status = resp.status();
-    let resp: DoggoQueryResult = resp.json().await?;
+    let resp = http.get(&url).json::<DoggoQueryResult>().await?;
+    let status = resp.status;
+    let resp = resp.body;
     match resp.status.as_str() {
-        "success" => Ok(QueryResult::Doggo { url: resp.message }),
+        "success" => {
+            let breed = breed_from_url(&resp.message);
+            Ok(Some(Media {
+                caption: breed.clone(),
+                variant: breed,
+                ..Media::from_url(resp.message)
+            }))
+        }
+        _ if status == StatusCode::NOT_FOUND => Ok(None),
         _ => {
             error!(?resp, ?status, "got non-success response from doggo API");
-            let msg = if let (Some(breed), StatusCode::NOT_FOUND) = (queried_breed, status) {
-                let breed_results: Vec<_> = all_breeds
-                    .iter()
-                    .filter(|b| b.contains(&breed.to_ascii_lowercase()))
-                    .collect();
-                if !breed_results.is_empty() {
-                    let result_texts = breed_results
-                        .into_iter()
-                        .map(|v| v.to_owned())
-                        .collect::<Vec<_>>()
-                        .join("\n");
-                    format!("Did you mean any of these:\n{}", result_texts)
-                } else {
-                    "Breed not found!".into()
-                }
-            } else {
-                resp.message
-            };
-            Ok(QueryResult::Error { msg })
+            Err(ApiError::Response(eyre!("doggo API: {}", resp.message)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::MockServer;
+
+    use super::*;
+    use crate::{
+        animal::MediaKind,
+        test_util::{fixture, mount, mount_delayed, test_http, TempPath},
+    };
+
+    async fn breeds(server: &MockServer) -> BTreeSet<String> {
+        mount(
+            server,
+            "/breeds/list/all",
+            200,
+            &fixture("dog_ceo/breeds_list_all.json"),
+        )
+        .await;
+        fetch_breeds(&test_http(), &server.uri()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn breeds_include_sub_breeds() {
+        let server = MockServer::start().await;
+        let breeds = breeds(&server).await;
+        assert!(breeds.contains("akita"));
+        assert!(breeds.contains("golden retriever"));
+        assert!(breeds.contains("yorkshire terrier"));
+        assert!(!breeds.contains("retriever"));
+    }
+
+    #[tokio::test]
+    async fn breeds_fall_back_to_last_known_good_copy() {
+        let cache = TempPath::new("breeds");
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            "/breeds/list/all",
+            200,
+            &fixture("dog_ceo/breeds_list_all.json"),
+        )
+        .await;
+        let fetched = load_breeds(&test_http(), &server.uri(), &cache).await;
+        assert!(fetched.contains("golden retriever"));
+
+        let down = MockServer::start().await;
+        mount(&down, "/breeds/list/all", 500, "{}").await;
+        let loaded = load_breeds(&test_http(), &down.uri(), &cache).await;
+        assert_eq!(loaded, fetched);
+
+        std::fs::remove_file(&cache).unwrap();
+        let empty = load_breeds(&test_http(), &down.uri(), &cache).await;
+        assert!(empty.is_empty());
+    }
+
+    fn dog_ceo(server: &MockServer) -> DogCeo {
+        DogCeo {
+            http: test_http(),
+            api_url: server.uri(),
+            breeds: Breeds::default(),
         }
     }
+
+    #[tokio::test]
+    async fn random_doggo() {
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            "/breeds/image/random",
+            200,
+            &fixture("dog_ceo/random.json"),
+        )
+        .await;
+        let media = dog_ceo(&server).fetch_random().await.unwrap();
+        assert_eq!(media.kind, MediaKind::Photo);
+        assert_eq!(media.caption.as_deref(), Some("golden retriever"));
+        assert!(media.url.ends_with(".jpg"), "{}", media.url);
+    }
+
+    #[tokio::test]
+    async fn sub_breed_is_queried_by_path() {
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            "/breed/retriever/golden/images/random",
+            200,
+            &fixture("dog_ceo/random.json"),
+        )
+        .await;
+        let media = dog_ceo(&server)
+            .fetch_variant("golden retriever")
+            .await
+            .unwrap();
+        assert!(media.is_some());
+    }
+
+    #[tokio::test]
+    async fn unknown_breed() {
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            "/breed/cat/images/random",
+            404,
+            &fixture("dog_ceo/breed_not_found.json"),
+        )
+        .await;
+        let media = dog_ceo(&server).fetch_variant("cat").await.unwrap();
+        assert!(media.is_none());
+    }
+
+    #[test]
+    fn breeds_from_urls() {
+        assert_eq!(
+            breed_from_url("https://images.dog.ceo/breeds/retriever-golden/n02099601_3004.jpg"),
+            Some("golden retriever".into())
+        );
+        assert_eq!(
+            breed_from_url("https://images.dog.ceo/breeds/akita/512px-Ainu_Dog.jpg"),
+            Some("akita".into())
+        );
+        assert_eq!(breed_from_url("https://example.com/doggo.jpg"), None);
+    }
+
+    #[test]
+    fn breed_paths() {
+        assert_eq!(breed_path("husky"), "husky");
+        assert_eq!(breed_path("golden retriever"), "retriever/golden");
+    }
+
+    #[tokio::test]
+    async fn malformed_response() {
+        let server = MockServer::start().await;
+        mount(
+            &server,
+            "/breeds/image/random",
+            200,
+            &fixture("malformed.json"),
+        )
+        .await;
+        let result = dog_ceo(&server).fetch_random().await;
+        assert!(matches!(result, Err(ApiError::Response(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let server = MockServer::start().await;
+        mount_delayed(
+            &server,
+            "/breeds/image/random",
+            &fixture("dog_ceo/random.json"),
+            Duration::from_secs(2),
+        )
+        .await;
+        let result = dog_ceo(&server).fetch_random().await;
+        assert!(matches!(result, Err(ApiError::Request(_))), "{:?}", result);
+    }
 }
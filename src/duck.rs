@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    animal::{AnimalProvider, Media},
+    error::ApiError,
+    http::Http,
+};
+
+/// Random ducks from random-d.uk (or something compatible).
+pub struct RandomDuck {
+    pub http: Http,
+    pub api_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Duck {
+    url: String,
+}
+
+#[async_trait]
+impl AnimalProvider for RandomDuck {
+    async fn fetch_random(&self) -> Result<Media, ApiError> {
+        let url = format!("{}/random", self.api_url);
+        let duck: Duck = self.http.get(&url).json().await?.body;
+        Ok(Media::from_url(duck.url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::MockServer;
+
+    use super::*;
+    use crate::{
+        animal::MediaKind,
+        test_util::{fixture, mount, test_http},
+    };
+
+    #[tokio::test]
+    async fn random_duck() {
+        let server = MockServer::start().await;
+        mount(&server, "/random", 200, &fixture("random_duck/random.json")).await;
+        let ducks = RandomDuck {
+            http: test_http(),
+            api_url: server.uri(),
+        };
+        let media = ducks.fetch_random().await.unwrap();
+        assert_eq!(media.kind, MediaKind::Animation);
+        assert_eq!(media.url, "https://random-d.uk/api/117.gif");
+    }
+}
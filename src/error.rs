@@ -0,0 +1,138 @@
+use std::fmt;
+
+use color_eyre::Report;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tbot::{
+    contexts::{
+        methods::{Callback, Message},
+        Command, DataCallback,
+    },
+    errors::MethodCall,
+    types::User,
+};
+use tracing::error;
+
+use crate::{http::CircuitOpen, ResultExt};
+
+/// Everything that can go wrong while answering with data from some API.
+///
+/// The details only go to the logs; users get a short message and an ID
+/// to find them.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The provider is considered down, so it wasn't even asked
+    Unavailable(CircuitOpen),
+    /// The request failed (connection, timeout, error status)
+    Request(Report),
+    /// The response wasn't what we expected (invalid JSON, missing fields)
+    Response(Report),
+    /// Telegram refused to send the result
+    Send(MethodCall),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unavailable(error) => error.fmt(f),
+            ApiError::Request(error) => write!(f, "request failed: {}", error),
+            ApiError::Response(error) => write!(f, "invalid response: {}", error),
+            ApiError::Send(error) => write!(f, "error sending result: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Unavailable(error) => Some(error),
+            ApiError::Request(error) | ApiError::Response(error) => {
+                let source: &(dyn std::error::Error + 'static) = error.as_ref();
+                Some(source)
+            }
+            ApiError::Send(error) => Some(error),
+        }
+    }
+}
+
+impl From<CircuitOpen> for ApiError {
+    fn from(error: CircuitOpen) -> Self {
+        ApiError::Unavailable(error)
+    }
+}
+impl From<MethodCall> for ApiError {
+    fn from(error: MethodCall) -> Self {
+        ApiError::Send(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Lang {
+    En,
+    De,
+}
+
+impl Lang {
+    /// The language of the user who sent the command.
+    pub fn of(ctx: &Command) -> Self {
+        ctx.from
+            .as_ref()
+            .and_then(|from| from.clone().user())
+            .map_or(Lang::En, |user| Lang::of_user(&user))
+    }
+    pub fn of_user(user: &User) -> Self {
+        match user.language_code.as_deref() {
+            Some(code) if code.starts_with("de") => Lang::De,
+            _ => Lang::En,
+        }
+    }
+}
+
+impl ApiError {
+    pub fn user_message(&self, lang: Lang, error_id: &str) -> String {
+        let msg = match (self, lang) {
+            (ApiError::Unavailable(_), Lang::En) => {
+                "That service is taking a break, try again later."
+            }
+            (ApiError::Unavailable(_), Lang::De) => {
+                "Der Dienst macht gerade Pause, versuch es später nochmal."
+            }
+            (ApiError::Request(_), Lang::En) => "I couldn't reach that service, try again later.",
+            (ApiError::Request(_), Lang::De) => {
+                "Der Dienst ist gerade nicht erreichbar, versuch es später nochmal."
+            }
+            (ApiError::Response(_), Lang::En) => "That service sent me something weird.",
+            (ApiError::Response(_), Lang::De) => "Der Dienst hat mir Unsinn geschickt.",
+            (ApiError::Send(_), Lang::En) => "Telegram didn't want me to send that.",
+            (ApiError::Send(_), Lang::De) => "Telegram wollte das nicht senden.",
+        };
+        match lang {
+            Lang::En => format!("{} (error {})", msg, error_id),
+            Lang::De => format!("{} (Fehler {})", msg, error_id),
+        }
+    }
+}
+
+/// A short random ID to find an error in the logs.
+fn error_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect()
+}
+
+/// Logs the error with a fresh ID and tells the user about it.
+pub async fn reply_with_error(ctx: &Command, error: ApiError) {
+    let error_id = error_id();
+    error!(%error_id, ?error, "error answering command");
+    let msg = error.user_message(Lang::of(ctx), &error_id);
+    ctx.send_message_in_reply(msg).call().await.log_err();
+}
+
+/// Like [`reply_with_error`], for button presses.
+pub async fn answer_with_error(ctx: &DataCallback, error: ApiError) {
+    let error_id = error_id();
+    error!(%error_id, ?error, "error answering callback");
+    let msg = error.user_message(Lang::of_user(&ctx.from), &error_id);
+    ctx.notify(msg).call().await.log_err();
+}
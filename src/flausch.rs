@@ -1,22 +1,30 @@
-use std::{collections::HashMap, sync::Arc};
-
-use color_eyre::{eyre::bail, Result};
-use reqwest::Client;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
 use serde::Deserialize;
-use tbot::{
-    contexts::{methods::Message, Command},
-    types::{file, input_file::Animation, message::Kind},
+
+use crate::{
+    animal::{AnimalProvider, Media, MediaKind},
+    error::ApiError,
+    http::Http,
 };
-use tokio::sync::Mutex;
 
-use crate::ResultExt;
+/// Random bunny loops from bunnies.io (or something compatible).
+pub struct Bunnies {
+    pub http: Http,
+    pub api_url: String,
+}
 
-pub async fn handler(ctx: Arc<Command>, state: Arc<(Client, Mutex<HashMap<String, file::Id>>)>) {
-    if let Err(error) = attempt_flausch(&ctx, &state.0, &state.1).await {
-        ctx.send_message_in_reply(format!("Error attempting flausch: {}", error))
-            .call()
-            .await
-            .log_err();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Mp4,
+    Gif,
+}
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Mp4 => "mp4",
+            Format::Gif => "gif",
+        }
     }
 }
 
@@ -28,34 +36,176 @@ struct FlauschResponse {
 
 #[derive(Debug, Deserialize)]
 struct FlauschMedia {
-    mp4: String,
-}
-
-const URL: &str = "https://api.bunnies.io/v2/loop/random/?media=mp4";
-async fn attempt_flausch(
-    ctx: &Arc<Command>,
-    client: &Client,
-    file_id_map: &Mutex<HashMap<String, file::Id>>,
-) -> Result<()> {
-    let resp: FlauschResponse = client.get(URL).send().await?.json().await?;
-    if let Some(id) = file_id_map.lock().await.get(&resp.id).cloned() {
-        ctx.send_animation(Animation::with_id(id)).call().await?;
-        return Ok(());
-    }
-    match ctx
-        .send_animation(Animation::with_url(&resp.media.mp4))
-        .call()
-        .await?
-        .kind
-    {
-        Kind::Animation { animation, .. } => {
-            file_id_map.lock().await.insert(resp.id, animation.file_id);
+    gif: Option<String>,
+    mp4: Option<String>,
+}
+impl FlauschMedia {
+    /// The URL in the preferred format, or else in the other one.
+    fn url(self, preferred: Format) -> Option<(Format, String)> {
+        let gif = self.gif.map(|url| (Format::Gif, url));
+        let mp4 = self.mp4.map(|url| (Format::Mp4, url));
+        match preferred {
+            Format::Gif => gif.or(mp4),
+            Format::Mp4 => mp4.or(gif),
+        }
+    }
+}
+
+impl Bunnies {
+    /// Fetches the bunny with the ID (or `random`); `None` if there is none.
+    async fn fetch(&self, id: &str, format: Format) -> Result<Option<Media>, ApiError> {
+        let url = format!("{}/loop/{}/?media=gif,mp4", self.api_url, id);
+        let bunny: FlauschResponse = match self.http.get(&url).json_if_found().await? {
+            Some(bunny) => bunny,
+            None => return Ok(None),
+        };
+        let (format, url) = bunny.media.url(format).ok_or_else(|| {
+            ApiError::Response(eyre!("bunny {} has neither gif nor mp4", bunny.id))
+        })?;
+        Ok(Some(Media {
+            kind: MediaKind::Animation,
+            url,
+            // The same bunny has a different file ID in each format
+            id: format!("{}.{}", bunny.id, format.name()),
+            caption: Some(format!("Bunny #{0} (/flausch {0})", bunny.id)),
+            variant: (format == Format::Gif).then(|| "gif".into()),
+        }))
+    }
+}
+
+#[async_trait]
+impl AnimalProvider for Bunnies {
+    async fn fetch_random(&self) -> Result<Media, ApiError> {
+        self.fetch("random", Format::Mp4)
+            .await?
+            .ok_or_else(|| ApiError::Response(eyre!("bunny API: no random bunny found")))
+    }
+    fn has_variants(&self) -> bool {
+        true
+    }
+    /// Understands a bunny ID and/or `gif` or `mp4`.
+    async fn fetch_variant(&self, variant: &str) -> Result<Option<Media>, ApiError> {
+        let mut id = "random";
+        let mut format = Format::Mp4;
+        for word in variant.split_whitespace() {
+            match word {
+                "gif" => format = Format::Gif,
+                "mp4" => format = Format::Mp4,
+                _ if word.chars().all(|c| c.is_ascii_digit()) => id = word,
+                _ => return Ok(None),
+            }
         }
-        // We need to handle this because it will turn stuff with audio into a video
-        Kind::Video { video, .. } => {
-            file_id_map.lock().await.insert(resp.id, video.file_id);
+        self.fetch(id, format).await
+    }
+    fn not_found(&self) -> &'static str {
+        "No such bunny! Try /flausch [id] [gif]"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::{
+        http::HttpConfig,
+        test_util::{fixture, mount, test_http},
+    };
+
+    fn bunnies(server: &MockServer) -> Bunnies {
+        Bunnies {
+            http: test_http(),
+            api_url: server.uri(),
         }
-        _ => bail!("got non-animation from animation"),
     }
-    Ok(())
+
+    #[tokio::test]
+    async fn random_bunny() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/loop/random/"))
+            .and(query_param("media", "gif,mp4"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(fixture("bunnies/random.json"), "application/json"),
+            )
+            .mount(&server)
+            .await;
+        let media = bunnies(&server).fetch_random().await.unwrap();
+        assert_eq!(media.kind, MediaKind::Animation);
+        assert_eq!(media.id, "42.mp4");
+        assert_eq!(media.url, "https://bunnies.media/mp4/42.mp4");
+        assert_eq!(media.caption.as_deref(), Some("Bunny #42 (/flausch 42)"));
+        assert_eq!(media.variant, None);
+
+        let media = bunnies(&server)
+            .fetch_variant("gif")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(media.id, "42.gif");
+        assert_eq!(media.url, "https://bunnies.media/gif/42.gif");
+        assert_eq!(media.variant.as_deref(), Some("gif"));
+    }
+
+    #[tokio::test]
+    async fn bunny_by_id() {
+        let server = MockServer::start().await;
+        mount(&server, "/loop/42/", 200, &fixture("bunnies/random.json")).await;
+        mount(&server, "/loop/7/", 404, "{}").await;
+        let bunnies = bunnies(&server);
+        let media = bunnies.fetch_variant("42 gif").await.unwrap().unwrap();
+        assert_eq!(media.url, "https://bunnies.media/gif/42.gif");
+        assert!(bunnies.fetch_variant("7").await.unwrap().is_none());
+        assert!(bunnies.fetch_variant("../7").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn missing_bunny_isnt_a_failure() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/loop/7/"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("Not Found"))
+            .mount(&server)
+            .await;
+        // Any failure would make the provider count as down
+        let bunnies = Bunnies {
+            http: Http::new(HttpConfig {
+                timeout: Duration::from_millis(200),
+                retries: 0,
+                failure_threshold: 1,
+                cooldown: Duration::from_secs(60),
+            }),
+            api_url: server.uri(),
+        };
+        assert!(bunnies.fetch_variant("7").await.unwrap().is_none());
+        assert!(bunnies.fetch_variant("7").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_other_format() {
+        let server = MockServer::start().await;
+        let body = r#"{"id": "5", "media": {"mp4": "https://bunnies.media/mp4/5.mp4"}}"#;
+        mount(&server, "/loop/random/", 200, body).await;
+        let media = bunnies(&server)
+            .fetch_variant("gif")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(media.url, "https://bunnies.media/mp4/5.mp4");
+        assert_eq!(media.variant, None);
+    }
+
+    #[tokio::test]
+    async fn malformed_response() {
+        let server = MockServer::start().await;
+        mount(&server, "/loop/random/", 200, &fixture("malformed.json")).await;
+        let result = bunnies(&server).fetch_random().await;
+        assert!(matches!(result, Err(ApiError::Response(_))), "{:?}", result);
+    }
 }
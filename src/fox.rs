@@ -0,0 +1,48 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    animal::{AnimalProvider, Media},
+    error::ApiError,
+    http::Http,
+};
+
+/// Random foxes from randomfox.ca (or something compatible).
+pub struct RandomFox {
+    pub http: Http,
+    pub api_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Floof {
+    image: String,
+}
+
+#[async_trait]
+impl AnimalProvider for RandomFox {
+    async fn fetch_random(&self) -> Result<Media, ApiError> {
+        let url = format!("{}/floof/", self.api_url);
+        let floof: Floof = self.http.get(&url).json().await?.body;
+        Ok(Media::from_url(floof.image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::MockServer;
+
+    use super::*;
+    use crate::test_util::{fixture, mount, test_http};
+
+    #[tokio::test]
+    async fn random_fox() {
+        let server = MockServer::start().await;
+        mount(&server, "/floof/", 200, &fixture("randomfox/floof.json")).await;
+        let foxes = RandomFox {
+            http: test_http(),
+            api_url: server.uri(),
+        };
+        let media = foxes.fetch_random().await.unwrap();
+        assert_eq!(media.url, "https://randomfox.ca/images/12.jpg");
+    }
+}
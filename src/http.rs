@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result,
+};
+use rand::{thread_rng, Rng};
+use reqwest::{Client, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HttpConfig {
+    /// Used for requests which don't set their own timeout
+    pub timeout: Duration,
+    /// How often transient errors are retried
+    pub retries: u32,
+    /// Consecutive failures after which a provider is considered down
+    pub failure_threshold: u32,
+    /// How long requests to a provider that is down are short-circuited
+    pub cooldown: Duration,
+}
+impl HttpConfig {
+    /// `HTTP_TIMEOUT_SECS` (default 10), `HTTP_RETRIES` (default 2),
+    /// `HTTP_FAILURE_THRESHOLD` (default 5), `HTTP_COOLDOWN_SECS` (default 60)
+    pub fn from_env() -> Result<Self> {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> Result<T>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            match env::var(name) {
+                Ok(val) => val.parse().wrap_err_with(|| format!("Invalid {}", name)),
+                Err(_) => Ok(default),
+            }
+        }
+        Ok(HttpConfig {
+            timeout: Duration::from_secs(var("HTTP_TIMEOUT_SECS", 10)?),
+            retries: var("HTTP_RETRIES", 2)?,
+            failure_threshold: var("HTTP_FAILURE_THRESHOLD", 5)?.max(1),
+            cooldown: Duration::from_secs(var("HTTP_COOLDOWN_SECS", 60)?),
+        })
+    }
+}
+
+/// A provider's base URL, which can be overridden with the env var `var`,
+/// e.g. to use a mirror or a local stand-in for tests.
+pub fn base_url(var: &str, default: &str) -> String {
+    env::var(var)
+        .unwrap_or_else(|_| default.into())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Returned instead of making a request while a provider is considered down.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub host: String,
+}
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is unavailable, not trying for now", self.host)
+    }
+}
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// Whether a request is checking if the provider is back, after the
+    /// cooldown; everything else is still short-circuited meanwhile.
+    probing: bool,
+}
+
+/// A shared HTTP client with timeouts, retries and a circuit breaker per
+/// provider (host).
+#[derive(Clone)]
+pub struct Http {
+    client: Client,
+    config: HttpConfig,
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
+}
+
+pub struct Response<T> {
+    pub status: StatusCode,
+    pub body: T,
+}
+
+impl Http {
+    pub fn new(config: HttpConfig) -> Self {
+        Http {
+            client: Client::new(),
+            config,
+            breakers: Default::default(),
+        }
+    }
+
+    pub fn get<'a>(&'a self, url: &'a str) -> Get<'a> {
+        Get {
+            http: self,
+            url,
+            headers: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    fn check_breaker(&self, host: &str) -> Result<(), CircuitOpen> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(host.to_string()).or_default();
+        match breaker.open_until {
+            Some(until) if Instant::now() < until => Err(CircuitOpen { host: host.into() }),
+            Some(_) if breaker.probing => Err(CircuitOpen { host: host.into() }),
+            Some(_) => {
+                // Half-open: let this one request through and decide by its
+                // result.
+                breaker.probing = true;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Every request that got past [`check_breaker`](Self::check_breaker)
+    /// must be recorded, or a probe would never finish.
+    fn record(&self, host: &str, success: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(host.to_string()).or_default();
+        if success {
+            *breaker = Breaker::default();
+            return;
+        }
+        breaker.failures += 1;
+        if breaker.probing {
+            // Still down, so trip again right away
+            breaker.probing = false;
+            breaker.open_until = Some(Instant::now() + self.config.cooldown);
+        } else if breaker.failures >= self.config.failure_threshold && breaker.open_until.is_none()
+        {
+            warn!(host, cooldown = ?self.config.cooldown, "provider seems to be down");
+            breaker.open_until = Some(Instant::now() + self.config.cooldown);
+        }
+    }
+}
+
+pub struct Get<'a> {
+    http: &'a Http,
+    url: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    timeout: Option<Duration>,
+}
+
+impl<'a> Get<'a> {
+    pub fn header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Overrides the default timeout (if `Some`).
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends the request, retrying timeouts, connection errors, 5xx and 429
+    /// responses, and deserializes the response body.
+    pub async fn json<T: DeserializeOwned>(self) -> Result<Response<T>, ApiError> {
+        let http = self.http;
+        let (host, resp) = self.send().await?;
+        let status = resp.status();
+        let body = resp.json().await;
+        http.record(&host, body.is_ok());
+        let body = body
+            .wrap_err_with(|| format!("Invalid response from {}", host))
+            .map_err(ApiError::Response)?;
+        Ok(Response { status, body })
+    }
+
+    /// Like [`json`](Self::json), but a 404 is `None` whatever its body, and
+    /// doesn't count as a failure of the provider.
+    pub async fn json_if_found<T: DeserializeOwned>(self) -> Result<Option<T>, ApiError> {
+        let http = self.http;
+        let (host, resp) = self.send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            http.record(&host, true);
+            return Ok(None);
+        }
+        let body = resp.json().await;
+        http.record(&host, body.is_ok());
+        body.wrap_err_with(|| format!("Invalid response from {}", host))
+            .map_err(ApiError::Response)
+            .map(Some)
+    }
+
+    /// Sends the request, retrying transient errors; returns the host and
+    /// the first response that isn't one.
+    async fn send(self) -> Result<(String, reqwest::Response), ApiError> {
+        let Get {
+            http,
+            url,
+            headers,
+            timeout,
+        } = self;
+        let host = Url::parse(url)
+            .wrap_err_with(|| format!("Invalid URL: {:?}", url))
+            .map_err(ApiError::Request)?
+            .host_str()
+            .unwrap_or_default()
+            .to_string();
+        http.check_breaker(&host)?;
+        let timeout = timeout.unwrap_or(http.config.timeout);
+        let mut attempt = 0;
+        loop {
+            let mut request = http.client.get(url).timeout(timeout);
+            for (name, value) in &headers {
+                request = request.header(*name, *value);
+            }
+            let result = request.send().await;
+            let transient = match &result {
+                Ok(resp) => {
+                    resp.status().is_server_error()
+                        || resp.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(error) => error.is_timeout() || error.is_connect(),
+            };
+            if !transient {
+                if result.is_err() {
+                    http.record(&host, false);
+                }
+                let resp = result
+                    .wrap_err_with(|| format!("Error requesting {}", host))
+                    .map_err(ApiError::Request)?;
+                return Ok((host, resp));
+            }
+            if attempt >= http.config.retries {
+                http.record(&host, false);
+                return Err(ApiError::Request(match result {
+                    Ok(resp) => eyre!("{} answered with {}", host, resp.status()),
+                    Err(error) => Report::new(error).wrap_err(format!("Error requesting {}", host)),
+                }));
+            }
+            attempt += 1;
+            let delay = backoff(attempt);
+            debug!(%host, attempt, ?delay, "retrying request");
+            time::sleep(delay).await;
+        }
+    }
+}
+
+/// Exponential backoff starting at 200ms, with up to 100% jitter.
+fn backoff(attempt: u32) -> Duration {
+    let base = 100u64 << attempt.min(6);
+    Duration::from_millis(base + thread_rng().gen_range(0..=base))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use wiremock::MockServer;
+
+    use super::*;
+    use crate::test_util::{mount, mount_delayed};
+
+    #[tokio::test]
+    async fn only_one_request_probes_a_provider_that_was_down() {
+        let server = MockServer::start().await;
+        mount(&server, "/down", 500, "{}").await;
+        mount_delayed(&server, "/slow", "{}", Duration::from_millis(200)).await;
+        let http = Http::new(HttpConfig {
+            timeout: Duration::from_secs(2),
+            retries: 0,
+            failure_threshold: 1,
+            cooldown: Duration::ZERO,
+        });
+        let down = format!("{}/down", server.uri());
+        let slow = format!("{}/slow", server.uri());
+
+        let result = http.get(&down).json::<Value>().await;
+        assert!(matches!(result, Err(ApiError::Request(_))));
+        let (probe, other) = tokio::join!(http.get(&slow).json::<Value>(), async {
+            time::sleep(Duration::from_millis(50)).await;
+            http.get(&slow).json::<Value>().await
+        });
+        assert!(probe.is_ok());
+        assert!(matches!(other, Err(ApiError::Unavailable(_))));
+        // The probe succeeded, so the provider is back
+        assert!(http.get(&slow).json::<Value>().await.is_ok());
+    }
+}
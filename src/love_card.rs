@@ -0,0 +1,196 @@
+//! Love test results drawn as images: a heart meter for two names, and a
+//! table for rankings.
+
+use std::io::Cursor;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use imageproc::{
+    drawing::{draw_filled_rect_mut, draw_text_mut, text_size},
+    rect::Rect,
+};
+use rusttype::{Font, Scale};
+
+/// DejaVu Sans Bold, which also covers Cyrillic and Greek names
+static FONT: &[u8] = include_bytes!("../res/fonts/DejaVuSans-Bold.ttf");
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 228, 236]);
+const STRIPE: Rgb<u8> = Rgb([255, 214, 226]);
+const FULL: Rgb<u8> = Rgb([220, 20, 60]);
+const EMPTY: Rgb<u8> = Rgb([255, 182, 193]);
+const TEXT: Rgb<u8> = Rgb([90, 10, 40]);
+const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+
+const CARD_WIDTH: u32 = 800;
+const CARD_HEIGHT: u32 = 500;
+/// Half the width of the heart's body
+const HEART_RADIUS: f32 = 160.0;
+
+const TABLE_WIDTH: u32 = 800;
+const ROW_HEIGHT: u32 = 44;
+const MARGIN: u32 = 20;
+const ROW_TEXT_SIZE: f32 = 24.0;
+/// Room for the score at the end of each row
+const SCORE_WIDTH: u32 = 110;
+
+/// The two names above a heart which is filled up to the score.
+pub fn heart_card(name1: &str, name2: &str, score: &str) -> Result<Vec<u8>> {
+    let font = font()?;
+    let mut img = RgbImage::from_pixel(CARD_WIDTH, CARD_HEIGHT, BACKGROUND);
+    let title = format!("{} + {}", name1, name2);
+    draw_centered(&mut img, &font, &title, TEXT, 56.0, 30);
+    let (cx, cy) = (CARD_WIDTH as f32 / 2.0, 300.0);
+    draw_heart(&mut img, cx, cy, HEART_RADIUS, percent(score));
+    let score = format!("{}%", score);
+    let y = cy as u32 - 60;
+    draw_centered(&mut img, &font, &score, WHITE, 80.0, y);
+    encode(img)
+}
+
+/// One row per pair with a bar for its score, plus a note about the `more`
+/// pairs which didn't fit.
+pub fn ranking_table(pairs: &[(String, String, String)], more: usize) -> Result<Vec<u8>> {
+    let font = font()?;
+    let rows = 1 + pairs.len() as u32 + u32::from(more > 0);
+    let mut img = RgbImage::from_pixel(TABLE_WIDTH, 2 * MARGIN + rows * ROW_HEIGHT, BACKGROUND);
+    let inner = TABLE_WIDTH - 2 * MARGIN;
+    draw_centered(&mut img, &font, "Love ranking", TEXT, 34.0, MARGIN);
+    for (i, (name1, name2, score)) in pairs.iter().enumerate() {
+        let y = MARGIN + (i as u32 + 1) * ROW_HEIGHT;
+        if i % 2 == 0 {
+            let stripe = Rect::at(MARGIN as i32, y as i32).of_size(inner, ROW_HEIGHT);
+            draw_filled_rect_mut(&mut img, stripe, STRIPE);
+        }
+        let bar = (inner as f32 * percent(score)).round() as u32;
+        if bar > 0 {
+            let bar = Rect::at(MARGIN as i32, (y + ROW_HEIGHT - 6) as i32).of_size(bar, 4);
+            draw_filled_rect_mut(&mut img, bar, FULL);
+        }
+        let label = format!("{}. {} x {}", i + 1, name1, name2);
+        let text_y = y + 8;
+        let (scale, _) = fit(&font, &label, ROW_TEXT_SIZE, inner - SCORE_WIDTH);
+        let x = (MARGIN + 8) as i32;
+        draw_text_mut(&mut img, TEXT, x, text_y as i32, scale, &font, &label);
+        let score = format!("{}%", score);
+        let (scale, width) = fit(&font, &score, ROW_TEXT_SIZE, SCORE_WIDTH);
+        let x = (MARGIN + inner - 8) as i32 - width;
+        draw_text_mut(&mut img, FULL, x, text_y as i32, scale, &font, &score);
+    }
+    if more > 0 {
+        let y = MARGIN + (pairs.len() as u32 + 1) * ROW_HEIGHT + 8;
+        let text = format!("…and {} more pairs", more);
+        let (scale, _) = fit(&font, &text, ROW_TEXT_SIZE, inner);
+        draw_text_mut(
+            &mut img,
+            TEXT,
+            (MARGIN + 8) as i32,
+            y as i32,
+            scale,
+            &font,
+            &text,
+        );
+    }
+    encode(img)
+}
+
+fn font() -> Result<Font<'static>> {
+    Font::try_from_bytes(FONT).ok_or_else(|| eyre!("Invalid bundled font"))
+}
+
+/// The score as a fraction, capped at 1 (scores can be above 100%).
+fn percent(score: &str) -> f32 {
+    score.parse::<f32>().unwrap_or(0.0).min(100.0) / 100.0
+}
+
+/// The biggest scale up to `max_size` at which the text fits into `width`,
+/// and the text's width at that scale.
+fn fit(font: &Font, text: &str, max_size: f32, width: u32) -> (Scale, i32) {
+    let scale = Scale::uniform(max_size);
+    let (text_width, _) = text_size(scale, font, text);
+    if text_width <= width as i32 {
+        return (scale, text_width);
+    }
+    let scale = Scale::uniform(max_size * width as f32 / text_width as f32);
+    (scale, text_size(scale, font, text).0)
+}
+
+/// Draws the text horizontally centered, shrunk to fit between the margins.
+fn draw_centered(
+    img: &mut RgbImage,
+    font: &Font,
+    text: &str,
+    color: Rgb<u8>,
+    max_size: f32,
+    y: u32,
+) {
+    let (scale, text_width) = fit(font, text, max_size, img.width() - 2 * MARGIN);
+    let x = (img.width() as i32 - text_width) / 2;
+    draw_text_mut(img, color, x, y as i32, scale, font, text);
+}
+
+/// A heart around (`cx`, `cy`), filled from the bottom up to `fill`.
+fn draw_heart(img: &mut RgbImage, cx: f32, cy: f32, radius: f32, fill: f32) {
+    // (u² + v² - 1)³ - u²v³ <= 0 is a heart reaching from v = -1 to about
+    // v = 1.25 (and u = ±1.14)
+    let (bottom, top) = (-1.0, 1.25);
+    let level = bottom + fill * (top - bottom);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let u = (x as f32 + 0.5 - cx) / radius;
+        let v = (cy - y as f32 - 0.5) / radius;
+        if u.abs() > 1.2 || !(bottom..=top).contains(&v) {
+            continue;
+        }
+        if (u * u + v * v - 1.0).powi(3) - u * u * v.powi(3) <= 0.0 {
+            *pixel = if v <= level { FULL } else { EMPTY };
+        }
+    }
+}
+
+fn encode(img: RgbImage) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(img)
+        .write_to(&mut out, ImageOutputFormat::Png)
+        .wrap_err("Error encoding image")?;
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heart_is_filled_up_to_the_score() {
+        let at = |score: &str, y: u32| {
+            let png = heart_card("Jürgen", "Юлия", score).unwrap();
+            let img = image::load_from_memory(&png).unwrap().to_rgb8();
+            assert_eq!(img.dimensions(), (CARD_WIDTH, CARD_HEIGHT));
+            *img.get_pixel(CARD_WIDTH / 2, y)
+        };
+        // Near the tip and near the top of the heart (between the bumps)
+        let (tip, top) = (
+            300 + HEART_RADIUS as u32 - 10,
+            300 - HEART_RADIUS as u32 / 2,
+        );
+        assert_eq!(at("05", tip), FULL);
+        assert_eq!(at("05", top), EMPTY);
+        assert_eq!(at("100", top), FULL);
+        assert_eq!(at("1000", top), FULL);
+    }
+
+    #[test]
+    fn ranking_table_has_a_row_per_pair() {
+        let pairs = vec![
+            ("Alice".into(), "Bob".into(), "99".into()),
+            ("Alice".into(), "Carol".into(), "42".into()),
+        ];
+        let png = ranking_table(&pairs, 0).unwrap();
+        let img = image::load_from_memory(&png).unwrap();
+        assert_eq!(img.height(), 2 * MARGIN + 3 * ROW_HEIGHT);
+        let png = ranking_table(&pairs, 3).unwrap();
+        let img = image::load_from_memory(&png).unwrap();
+        assert_eq!(img.height(), 2 * MARGIN + 4 * ROW_HEIGHT);
+    }
+}
@@ -0,0 +1,327 @@
+//! Every love test result per chat, for `/lovetop` and `/lovehistory`.
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tbot::{
+    contexts::{
+        methods::{Callback, Message},
+        DataCallback,
+    },
+    types::{
+        callback::Origin,
+        chat,
+        keyboard::inline::{Button, ButtonKind, Keyboard},
+        parameters::Text,
+    },
+    EventLoop,
+};
+use tokio::sync::Mutex;
+use tracing::error;
+
+use crate::{
+    love_test::{normalize, score_key},
+    members::{escape_html, Members},
+    persist::{load_json, save_json},
+    ResultExt,
+};
+
+pub static HISTORY_USAGE: &str = "/lovehistory <name or @username>";
+/// Prefix of the callback data of `/lovetop` page buttons.
+const TOP_PREFIX: &str = "lovetop:";
+/// Prefix of the callback data of `/lovehistory` page buttons.
+const HISTORY_PREFIX: &str = "lovehistory:";
+/// Telegram's limit for callback data
+const MAX_CALLBACK_DATA: usize = 64;
+const RECORDS_PER_PAGE: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// As they were written the first time
+    pub names: (String, String),
+    pub score: String,
+    /// How often the pair was tested
+    pub times: u32,
+}
+
+/// Records by chat ID and (normalised) pair of names.
+type Chats = BTreeMap<i64, BTreeMap<String, Record>>;
+
+#[derive(Clone)]
+pub struct LoveHistory {
+    path: PathBuf,
+    chats: Arc<Mutex<Chats>>,
+}
+
+impl LoveHistory {
+    pub async fn load(path: PathBuf) -> Result<Self> {
+        let chats = load_json(&path).await?;
+        Ok(LoveHistory {
+            path,
+            chats: Arc::new(Mutex::new(chats)),
+        })
+    }
+
+    /// Records the results (best first) and tells whether the best one beats
+    /// the chat's previous record.
+    pub async fn record(&self, chat: chat::Id, results: &[(&str, &str, &str)]) -> bool {
+        let mut chats = self.chats.lock().await;
+        let records = chats.entry(chat.0).or_default();
+        let best = records
+            .values()
+            .map(|record| score_key(&record.score))
+            .max();
+        let new_record = match (best, results.first()) {
+            (Some(best), Some((_, _, score))) => score_key(score) > best,
+            _ => false,
+        };
+        for (name1, name2, score) in results {
+            let record = records
+                .entry(pair_key(name1, name2))
+                .or_insert_with(|| Record {
+                    names: (name1.to_string(), name2.to_string()),
+                    score: score.to_string(),
+                    times: 0,
+                });
+            record.times += 1;
+        }
+        if let Err(error) = save_json(&self.path, &*chats).await {
+            error!(?error, path = ?self.path, "error saving love history");
+        }
+        new_record
+    }
+
+    /// The chat's best pairs.
+    pub async fn top(&self, chat: chat::Id) -> Vec<Record> {
+        let chats = self.chats.lock().await;
+        sorted(chats.get(&chat.0).into_iter().flat_map(BTreeMap::values))
+    }
+
+    /// The best pairs with someone of that name.
+    pub async fn of(&self, chat: chat::Id, name: &str) -> Vec<Record> {
+        let name = normalize(name);
+        let chats = self.chats.lock().await;
+        let records = chats.get(&chat.0).into_iter().flat_map(BTreeMap::values);
+        sorted(records.filter(|record| {
+            normalize(&record.names.0) == name || normalize(&record.names.1) == name
+        }))
+    }
+}
+
+/// The same for both orders of the names
+fn pair_key(name1: &str, name2: &str) -> String {
+    let (name1, name2) = (normalize(name1), normalize(name2));
+    // Normalised names have no whitespace, so this is unambiguous
+    if name1 < name2 {
+        format!("{} {}", name1, name2)
+    } else {
+        format!("{} {}", name2, name1)
+    }
+}
+
+fn sorted<'a>(records: impl Iterator<Item = &'a Record>) -> Vec<Record> {
+    let mut records: Vec<Record> = records.cloned().collect();
+    records.sort_by(|a, b| score_key(&b.score).cmp(&score_key(&a.score)));
+    records
+}
+
+pub fn register(bot: &mut EventLoop, history: LoveHistory, members: Members) {
+    let top = history.clone();
+    bot.command("lovetop", move |ctx| {
+        let history = top.clone();
+        async move {
+            let (text, keyboard) = top_page(&history.top(ctx.chat.id).await, 0);
+            ctx.send_message(Text::with_html(text))
+                .reply_markup(keyboard)
+                .call()
+                .await
+                .log_err();
+        }
+    });
+    let of = history.clone();
+    bot.command("lovehistory", move |ctx| {
+        let history = of.clone();
+        let members = members.clone();
+        async move {
+            let mut name = ctx.text.value.trim().to_string();
+            if name.is_empty() {
+                ctx.send_message_in_reply(HISTORY_USAGE)
+                    .call()
+                    .await
+                    .log_err();
+                return;
+            }
+            if name.starts_with('@') {
+                if let Some(member) = members.find(ctx.chat.id, &name) {
+                    name = member.first_name;
+                }
+            }
+            let records = history.of(ctx.chat.id, &name).await;
+            let (text, keyboard) = history_page(&name, &records, 0);
+            ctx.send_message(Text::with_html(text))
+                .reply_markup(keyboard)
+                .call()
+                .await
+                .log_err();
+        }
+    });
+    bot.data_callback(move |ctx| callback_handler(ctx, history.clone()));
+}
+
+async fn callback_handler(ctx: Arc<DataCallback>, history: LoveHistory) {
+    let msg = match &ctx.origin {
+        Origin::Message(msg) => msg,
+        Origin::Inline(_) => return,
+    };
+    let (text, keyboard) = if let Some(page) = ctx.data.strip_prefix(TOP_PREFIX) {
+        top_page(&history.top(msg.chat.id).await, page.parse().unwrap_or(0))
+    } else if let Some(data) = ctx.data.strip_prefix(HISTORY_PREFIX) {
+        let (page, name) = data.split_once(':').unwrap_or((data, ""));
+        let records = history.of(msg.chat.id, name).await;
+        history_page(name, &records, page.parse().unwrap_or(0))
+    } else {
+        return;
+    };
+    ctx.ignore().call().await.log_err();
+    ctx.bot
+        .edit_message_text(msg.chat.id, msg.id, Text::with_html(text))
+        .reply_markup(keyboard)
+        .call()
+        .await
+        .log_err_msg("error turning love history page");
+}
+
+fn top_page(records: &[Record], page: usize) -> (String, Keyboard) {
+    if records.is_empty() {
+        let text = "Nobody has tested their love here yet. Try /testlove!".to_string();
+        return (text, Keyboard::new(Vec::new()));
+    }
+    let lines = records
+        .iter()
+        .map(|Record { names, score, .. }| {
+            let (name1, name2) = (escape_html(&names.0), escape_html(&names.1));
+            format!("{} x {} ({}%)", name1, name2, score)
+        })
+        .collect::<Vec<_>>();
+    paginate("💘 The best couples", &lines, page, |page| {
+        Some(format!("{}{}", TOP_PREFIX, page))
+    })
+}
+
+fn history_page(name: &str, records: &[Record], page: usize) -> (String, Keyboard) {
+    if records.is_empty() {
+        let text = format!("{} hasn't been tested here yet.", escape_html(name));
+        return (text, Keyboard::new(Vec::new()));
+    }
+    let normalized = normalize(name);
+    let lines = records
+        .iter()
+        .map(|Record { names, score, .. }| {
+            let other = if normalize(&names.0) == normalized {
+                &names.1
+            } else {
+                &names.0
+            };
+            format!("{} ({}%)", escape_html(other), score)
+        })
+        .collect::<Vec<_>>();
+    let title = format!("💘 The best matches of {}", escape_html(name));
+    paginate(&title, &lines, page, |page| {
+        // Long names don't fit into the callback data; they only get one page
+        Some(format!("{}{}:{}", HISTORY_PREFIX, page, name))
+            .filter(|data| data.len() <= MAX_CALLBACK_DATA)
+    })
+}
+
+/// Numbers the lines and shows a page of them, with buttons for the previous
+/// and next pages if `nav_data` has callback data for them.
+fn paginate(
+    title: &str,
+    lines: &[String],
+    page: usize,
+    nav_data: impl Fn(usize) -> Option<String>,
+) -> (String, Keyboard) {
+    let pages = (lines.len().max(1) - 1) / RECORDS_PER_PAGE + 1;
+    let page = page.min(pages - 1);
+    let mut text = format!("{} (page {}/{}):\n\n", title, page + 1, pages);
+    for (i, line) in lines
+        .iter()
+        .enumerate()
+        .skip(page * RECORDS_PER_PAGE)
+        .take(RECORDS_PER_PAGE)
+    {
+        text.push_str(&format!("{}. {}\n", i + 1, line));
+    }
+    let nav = |label: &str, page: usize| {
+        nav_data(page).map(|data| Button::new(label, ButtonKind::CallbackData(data)))
+    };
+    let mut nav_row = Vec::new();
+    if page > 0 {
+        nav_row.extend(nav("◀️ Previous", page - 1));
+    }
+    if page + 1 < pages {
+        nav_row.extend(nav("Next ▶️", page + 1));
+    }
+    let rows = if nav_row.is_empty() {
+        Vec::new()
+    } else {
+        vec![nav_row]
+    };
+    (text, Keyboard::new(rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    #[tokio::test]
+    async fn results_are_recorded_per_chat() {
+        let path = TempPath::new("love-history");
+        let history = LoveHistory::load(path.to_path_buf()).await.unwrap();
+        let chat = chat::Id(-1);
+
+        // The first result can't beat anything
+        assert!(!history.record(chat, &[("Alice", "Bob", "42")]).await);
+        assert!(!history.record(chat, &[("bob", "ALICE", "42")]).await);
+        let results = [("Carol", "Alice", "100"), ("Carol", "Bob", "07")];
+        assert!(history.record(chat, &results).await);
+        assert!(!history.record(chat::Id(-2), &[("Dave", "Eve", "99")]).await);
+
+        let top = history.top(chat).await;
+        let scores = top
+            .iter()
+            .map(|record| record.score.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(scores, ["100", "42", "07"]);
+        assert_eq!(top[1].names, ("Alice".to_string(), "Bob".to_string()));
+        assert_eq!(top[1].times, 2);
+        let bobs = history.of(chat, "BOB").await;
+        assert_eq!(bobs, [top[1].clone(), top[2].clone()]);
+
+        // Everything survives a restart
+        let reloaded = LoveHistory::load(path.to_path_buf()).await.unwrap();
+        assert_eq!(reloaded.top(chat).await, top);
+    }
+
+    #[test]
+    fn pages() {
+        let record = |i: usize| Record {
+            names: (format!("<{}>", i), "Bob".into()),
+            score: (100 - i).to_string(),
+            times: 1,
+        };
+        let records = (0..25).map(record).collect::<Vec<_>>();
+        let (text, _) = top_page(&records, 1);
+        assert!(text.starts_with("💘 The best couples (page 2/3):\n\n11. &lt;10&gt; x Bob (90%)\n"));
+        assert_eq!(text.lines().count(), 2 + RECORDS_PER_PAGE);
+        let (text, _) = history_page("bob", &records, 7);
+        assert!(
+            text.starts_with("💘 The best matches of bob (page 3/3):\n\n21. &lt;20&gt; (80%)\n")
+        );
+        assert_eq!(text.lines().count(), 2 + 5);
+        let (text, _) = history_page("Carol", &[], 0);
+        assert_eq!(text, "Carol hasn't been tested here yet.");
+    }
+}
@@ -1,55 +1,231 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    sync::Arc,
+use std::sync::Arc;
+
+use tbot::{
+    contexts::{methods::Message, Command},
+    types::{input_file::Photo, parameters::Text},
+    EventLoop,
+};
+use tokio::task;
+use tracing::error;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    love_card,
+    love_history::LoveHistory,
+    members::{escape_html, Member, Members},
+    ResultExt,
 };
 
-use tbot::contexts::{methods::Message, Command};
+pub static USAGE: &str = "/testlove <list of names or @usernames> (separated by spaces, commas or lines; \"quote names\" with spaces), or reply to someone. With more than two names, only the best pairs are shown; the rest are in /lovetop";
+/// More would make for a long list (and a lot of pairs to compute)
+const MAX_NAMES: usize = 20;
+/// Pairs shown by `rank_love`
+const MAX_RANKED: usize = 15;
+const NEW_RECORD: &str = " 🏆 New record!";
 
-use crate::ResultExt;
+/// Someone to be tested: a chat member we know, or just a name.
+enum Lover {
+    Member(Member),
+    Name(String),
+}
+impl Lover {
+    /// The name that is tested
+    fn name(&self) -> &str {
+        match self {
+            Lover::Member(member) => &member.first_name,
+            Lover::Name(name) => name,
+        }
+    }
+    /// A mention for members, so they get notified
+    fn show(&self) -> String {
+        match self {
+            Lover::Member(member) => member.mention(),
+            Lover::Name(name) => escape_html(name),
+        }
+    }
+}
+impl AsRef<str> for Lover {
+    fn as_ref(&self) -> &str {
+        self.name()
+    }
+}
 
-pub static USAGE: &str = "/testlove <list of names>";
-pub async fn handler(ctx: Arc<Command>) {
-    if ctx.text.value.is_empty() {
+/// With `cards`, results are sent as images.
+pub fn register(bot: &mut EventLoop, members: Members, history: LoveHistory, cards: bool) {
+    bot.command("testlove", move |ctx| {
+        handler(ctx, members.clone(), history.clone(), cards)
+    });
+}
+
+async fn handler(ctx: Arc<Command>, members: Members, history: LoveHistory, cards: bool) {
+    let sender = ctx.from.clone().and_then(|from| from.user());
+    let replied = ctx
+        .reply_to
+        .as_ref()
+        .and_then(|msg| msg.from.clone())
+        .and_then(|from| from.user());
+    for user in sender.iter().chain(&replied) {
+        members.record(ctx.chat.id, user);
+    }
+    let mut lovers: Vec<Lover> = parse_names(&ctx.text.value)
+        .into_iter()
+        .map(|name| {
+            let member = if name.starts_with('@') {
+                members.find(ctx.chat.id, &name)
+            } else {
+                None
+            };
+            match member {
+                Some(member) => Lover::Member(member),
+                None => Lover::Name(name),
+            }
+        })
+        .collect();
+    if let Some(user) = &replied {
+        lovers.insert(0, Lover::Member(Member::new(user)));
+    }
+    if lovers.is_empty() {
         ctx.send_message_in_reply(USAGE).call().await.log_err();
         return;
     }
-    let names: Vec<&str> = if ctx.text.value.contains('\n') {
-        ctx.text.value.split('\n').collect()
-    } else {
-        ctx.text.value.split(' ').collect()
-    };
-    if names.len() < 2 {
+    // A single name is tested against the sender
+    if let (1, Some(user)) = (lovers.len(), &sender) {
+        lovers.insert(0, Lover::Member(Member::new(user)));
+    }
+    if lovers.len() < 2 {
         ctx.send_message_in_reply("Please provide at least two names.")
             .call()
             .await
             .log_err();
         return;
     }
-    let result = if names.len() > 2 {
-        rank_love(&names)
+    if lovers.len() > MAX_NAMES {
+        let msg = format!("That's too many names, please test at most {}.", MAX_NAMES);
+        ctx.send_message_in_reply(msg).call().await.log_err();
+        return;
+    }
+    let results = if lovers.len() > 2 {
+        rank_pairs(&lovers)
     } else {
-        let str_result = test_love(names[0], names[1]);
-        format!("{} and {} fit {}%.", names[0], names[1], str_result)
+        let str_result = test_love(lovers[0].as_ref(), lovers[1].as_ref());
+        vec![(&lovers[0], &lovers[1], str_result)]
     };
-    ctx.send_message(result).call().await.log_err();
+    let recorded: Vec<(&str, &str, &str)> = results
+        .iter()
+        .map(|(name1, name2, result)| (name1.as_ref(), name2.as_ref(), result.as_str()))
+        .collect();
+    let new_record = history.record(ctx.chat.id, &recorded).await;
+    let result = if lovers.len() > 2 {
+        rank_love(&results, Lover::show, new_record)
+    } else {
+        let mut result = format!(
+            "{} and {} fit {}%.",
+            lovers[0].show(),
+            lovers[1].show(),
+            results[0].2
+        );
+        if new_record {
+            result.push_str(NEW_RECORD);
+        }
+        result
+    };
+    if cards {
+        match draw_card(&results).await {
+            Ok(bytes) => {
+                let mut photo = Photo::with_bytes(bytes);
+                if lovers.len() == 2 {
+                    photo = photo.caption(Text::with_html(result));
+                } else if new_record {
+                    photo = photo.caption(NEW_RECORD.trim());
+                }
+                ctx.send_photo(photo).call().await.log_err();
+                return;
+            }
+            Err(error) => error!(?error, "error drawing love card, sending text instead"),
+        }
+    }
+    ctx.send_message(Text::with_html(result))
+        .call()
+        .await
+        .log_err();
+}
+
+/// A heart for a single pair, or a table of the best pairs.
+async fn draw_card(results: &[(&Lover, &Lover, String)]) -> color_eyre::Result<Vec<u8>> {
+    let pairs: Vec<(String, String, String)> = results
+        .iter()
+        .take(MAX_RANKED)
+        .map(|(name1, name2, result)| {
+            (
+                name1.name().to_string(),
+                name2.name().to_string(),
+                result.clone(),
+            )
+        })
+        .collect();
+    let more = results.len().saturating_sub(MAX_RANKED);
+    task::spawn_blocking(move || match pairs.as_slice() {
+        [(name1, name2, result)] => love_card::heart_card(name1, name2, result),
+        pairs => love_card::ranking_table(pairs, more),
+    })
+    .await?
+}
+
+/// Splits the names by lines, or else by commas, or else by whitespace.
+/// Quoted names are kept together in any case.
+fn parse_names(text: &str) -> Vec<String> {
+    fn split(text: &str, is_separator: fn(char) -> bool) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut name = String::new();
+        let mut quoted = false;
+        for c in text.chars() {
+            match c {
+                '"' | '“' | '”' | '„' => quoted = !quoted,
+                _ if is_separator(c) && !quoted => names.push(std::mem::take(&mut name)),
+                _ => name.push(c),
+            }
+        }
+        names.push(name);
+        names
+            .iter()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+    let separators: [fn(char) -> bool; 3] = [|c| c == '\n', |c| c == ',', char::is_whitespace];
+    let mut names = Vec::new();
+    for is_separator in separators {
+        names = split(text, is_separator);
+        if names.len() > 1 {
+            break;
+        }
+    }
+    names
+}
+
+/// The name as it is counted: normalised, case-folded and without
+/// whitespace, so that e.g. "Jürgen" and "JÜRGEN" are the same person.
+pub fn normalize(name: &str) -> String {
+    name.nfkc()
+        .flat_map(char::to_lowercase)
+        .flat_map(char::to_uppercase)
+        .filter(|c| !c.is_whitespace())
+        .nfc()
+        .collect()
 }
 
 fn get_count(name1: &str, name2: &str) -> Vec<usize> {
     const LOVE_VAL: &str = "ILOVE";
-    let mut map = BTreeMap::new();
-    let names = name1.to_ascii_uppercase() + &name2.to_ascii_uppercase() + LOVE_VAL;
+    let mut map = std::collections::BTreeMap::new();
+    let names = normalize(name1) + &normalize(name2) + LOVE_VAL;
     for ch in names.chars() {
-        if let Some(val) = map.get_mut(&ch) {
-            *val += 1;
-        } else {
-            map.insert(ch, 1);
-        }
+        *map.entry(ch).or_insert(0) += 1;
     }
     map.values().copied().collect()
 }
 
-fn test_love(name1: &str, name2: &str) -> String {
-    let (name1, name2) = if name1 > name2 {
+pub fn test_love(name1: &str, name2: &str) -> String {
+    let (name1, name2) = if normalize(name1) > normalize(name2) {
         (name1, name2)
     } else {
         (name2, name1)
@@ -78,26 +254,196 @@ fn test_love(name1: &str, name2: &str) -> String {
     count[0].to_string() + &count[1].to_string()
 }
 
-fn rank_love(names: &[&str]) -> String {
-    let mut combos = HashMap::new();
-    for name1 in names.iter() {
-        for name2 in names.iter() {
-            if combos.contains_key(&(*name1, *name2))
-                || combos.contains_key(&(*name2, *name1))
-                || *name1 == *name2
-            {
-                continue;
-            }
-            combos.insert((*name1, *name2), test_love(name1, name2));
+/// Orders results like "05" < "42" < "100".
+pub fn score_key(result: &str) -> (usize, &str) {
+    let digits = result.trim_start_matches('0');
+    (digits.len(), digits)
+}
+
+/// Every pair of (different) names with their result, best first.
+fn rank_pairs<S: AsRef<str>>(names: &[S]) -> Vec<(&S, &S, String)> {
+    let mut pairs = Vec::new();
+    let mut seen = Vec::new();
+    for name in names {
+        let normalized = normalize(name.as_ref());
+        if seen.contains(&normalized) {
+            continue;
+        }
+        seen.push(normalized);
+        pairs.push(name);
+    }
+    let mut combos = Vec::new();
+    for (i, name1) in pairs.iter().enumerate() {
+        for name2 in &pairs[i + 1..] {
+            let result = test_love(name1.as_ref(), name2.as_ref());
+            combos.push((*name1, *name2, result));
         }
     }
-    let mut tmp = combos.iter().collect::<Vec<_>>();
-    tmp.sort_by(|t1, t2| t2.1.partial_cmp(t1.1).unwrap());
-    tmp.iter()
+    combos.sort_by(|(_, _, a), (_, _, b)| score_key(b).cmp(&score_key(a)));
+    combos
+}
+
+/// Only the best pairs are shown; all of them end up in `/lovetop` anyway.
+fn rank_love<S>(
+    ranked: &[(&S, &S, String)],
+    show: impl Fn(&S) -> String,
+    new_record: bool,
+) -> String {
+    let mut lines = ranked
+        .iter()
+        .take(MAX_RANKED)
         .enumerate()
-        .map(|(i, ((name1, name2), result))| {
-            format!("{}. {} x {} ({}%)", i + 1, name1, name2, result)
+        .map(|(i, (name1, name2, result))| {
+            format!("{}. {} x {} ({}%)", i + 1, show(name1), show(name2), result)
         })
-        .collect::<Vec<_>>()
-        .join("\n")
+        .collect::<Vec<_>>();
+    if let (true, Some(best)) = (new_record, lines.first_mut()) {
+        best.push_str(NEW_RECORD);
+    }
+    if ranked.len() > MAX_RANKED {
+        let more = ranked.len() - MAX_RANKED;
+        lines.push(format!("…and {} more pairs, see /lovetop", more));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::test_util::{bot_api::FakeBotApi, TempPath};
+
+    #[tokio::test]
+    async fn replies_and_mentions_are_tested_against_the_sender() {
+        let tg = FakeBotApi::start().await;
+        let path = TempPath::new("love-test");
+        let history = LoveHistory::load(path.to_path_buf()).await.unwrap();
+        let mut bot = tg.bot().event_loop();
+        register(&mut bot, Members::default(), history, false);
+        tg.run(bot);
+        let group = tg.group("Lovers");
+        let alice = tg.user("Alice");
+        let bob = tg.user("Bob");
+        let expected = format!(
+            "<a href=\"tg://user?id={}\">Bob</a> and <a href=\"tg://user?id={}\">Alice</a> fit {}%.",
+            bob.id,
+            alice.id,
+            test_love("Bob", "Alice")
+        );
+
+        let hi = alice.sends("hi").in_chat(&group).send();
+        bob.sends("/testlove")
+            .in_chat(&group)
+            .replying_to(&hi)
+            .send();
+        let call = tg.expect("sendMessage").await;
+        assert_eq!(call.param("parse_mode").as_deref(), Some("HTML"));
+        assert_eq!(call.text(), expected);
+
+        bob.sends("/testlove @ALICE").in_chat(&group).send();
+        assert_eq!(tg.expect("sendMessage").await.text(), expected);
+
+        bob.sends("/testlove @carol <Dave>").in_chat(&group).send();
+        let text = tg.expect("sendMessage").await.text();
+        assert!(text.starts_with("@carol and &lt;Dave&gt; fit "), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn results_can_be_sent_as_cards() {
+        let tg = FakeBotApi::start().await;
+        let path = TempPath::new("love-cards");
+        let history = LoveHistory::load(path.to_path_buf()).await.unwrap();
+        let mut bot = tg.bot().event_loop();
+        register(&mut bot, Members::default(), history, true);
+        tg.run(bot);
+        let bob = tg.user("Bob");
+
+        bob.sends("/testlove Alice").send();
+        let call = tg.expect("sendPhoto").await;
+        let caption = call.param("caption").unwrap();
+        assert!(caption.ends_with(&format!("Alice fit {}%.", test_love("Alice", "Bob"))));
+        assert!(call.files["photo"].starts_with(b"\x89PNG"));
+
+        bob.sends("/testlove Alice, Carol, Dave").send();
+        let call = tg.expect("sendPhoto").await;
+        assert!(call.files["photo"].starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn names_are_split_by_lines_commas_or_spaces() {
+        assert_eq!(parse_names("Anna Bob"), ["Anna", "Bob"]);
+        assert_eq!(parse_names("Anna Maria, Bob"), ["Anna Maria", "Bob"]);
+        assert_eq!(
+            parse_names("Anna Maria\nBob, Jr.\n"),
+            ["Anna Maria", "Bob, Jr."]
+        );
+        assert_eq!(parse_names("\"Anna Maria\" Bob"), ["Anna Maria", "Bob"]);
+        assert_eq!(parse_names("„Smith, John“, Jane"), ["Smith, John", "Jane"]);
+        assert_eq!(parse_names("  Юлия   Jürgen "), ["Юлия", "Jürgen"]);
+        assert_eq!(parse_names("Anna"), ["Anna"]);
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        assert_eq!(normalize("Jürgen"), "JÜRGEN");
+        assert_eq!(normalize("Ju\u{308}rgen"), "JÜRGEN");
+        assert_eq!(normalize("юлия"), "ЮЛИЯ");
+        assert_eq!(normalize("Anna Maria"), "ANNAMARIA");
+        assert_eq!(normalize("Straße"), normalize("STRASSE"));
+        assert_eq!(test_love("Jürgen", "Юлия"), test_love("JÜRGEN", "юлия"));
+    }
+
+    #[test]
+    fn ranking_is_numeric() {
+        let mut results = vec!["9", "100", "05", "42"];
+        results.sort_by(|a, b| score_key(a).cmp(&score_key(b)));
+        assert_eq!(results, ["05", "9", "42", "100"]);
+    }
+
+    fn name() -> impl Strategy<Value = String> {
+        "[a-zA-ZäöüßÄÖÜéа-яА-ЯёЁ ]{1,12}"
+    }
+
+    proptest! {
+        #[test]
+        fn love_is_symmetric(a in name(), b in name()) {
+            prop_assert_eq!(test_love(&a, &b), test_love(&b, &a));
+        }
+
+        #[test]
+        fn love_ignores_case_and_normalization(a in name(), b in name()) {
+            let expected = test_love(&a, &b);
+            prop_assert_eq!(&test_love(&a.to_uppercase(), &b.to_lowercase()), &expected);
+            let decomposed: String = a.nfd().collect();
+            prop_assert_eq!(&test_love(&decomposed, &b), &expected);
+        }
+
+        #[test]
+        fn love_is_a_number(a in name(), b in name()) {
+            let result = test_love(&a, &b);
+            prop_assert!(result.len() >= 2, "{:?}", result);
+            prop_assert!(result.chars().all(|c| c.is_ascii_digit()), "{:?}", result);
+        }
+
+        #[test]
+        fn ranking_covers_each_pair_once(names in prop::collection::vec(name(), 0..8)) {
+            let distinct = names
+                .iter()
+                .map(|name| normalize(name))
+                .collect::<std::collections::HashSet<_>>()
+                .len();
+            let ranked = rank_pairs(&names);
+            prop_assert_eq!(ranked.len(), distinct * distinct.saturating_sub(1) / 2);
+            for (name1, name2, result) in &ranked {
+                prop_assert_ne!(normalize(name1), normalize(name2));
+                prop_assert_eq!(result, &test_love(name2, name1));
+            }
+            for pair in ranked.windows(2) {
+                prop_assert!(score_key(&pair[0].2) >= score_key(&pair[1].2));
+            }
+            let lines = rank_love(&ranked, String::clone, false).lines().count();
+            prop_assert_eq!(lines, ranked.len().min(MAX_RANKED + 1));
+        }
+    }
 }
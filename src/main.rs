@@ -1,25 +1,45 @@
-use std::{collections::HashMap, sync::Arc};
+use std::path::Path;
 
-use color_eyre::{
-    eyre::{eyre, WrapErr},
-    Report, Result,
-};
-use reqwest::Client;
-use serde_json::Value;
-use tbot::{
-    contexts::{methods::Message, Command},
-    types::file,
-};
-use tokio::sync::Mutex;
+use color_eyre::{eyre::WrapErr, Report, Result};
 use tracing::{error, info};
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt};
 
+use animal::{Animal, Animals};
 use bot::GodfishBotBuilder;
+use cat::TheCatApi;
+use couple::CoupleConfig;
+use doggo::{Breeds, BreedsConfig, DogCeo};
+use duck::RandomDuck;
+use flausch::Bunnies;
+use fox::RandomFox;
+use http::{base_url, Http, HttpConfig};
+use love_history::LoveHistory;
+use members::Members;
+use prefetch::PoolConfig;
+use preprocess::ImageLimits;
 
+mod animal;
+mod api;
 mod bot;
+mod cat;
+mod couple;
 mod doggo;
+mod duck;
+mod error;
 mod flausch;
+mod fox;
+mod http;
+mod love_card;
+mod love_history;
 mod love_test;
+mod members;
+mod overlay;
+mod persist;
+mod prefetch;
+mod preprocess;
+mod stickers;
+#[cfg(test)]
+mod test_util;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -36,8 +56,10 @@ async fn main() -> Result<()> {
     });
 
     info!("Starting up bot");
+    info!("Loading API commands...");
+    let apis = api::load_commands(Path::new("res/apis.json")).await?;
     info!("Setting up commands...");
-    let mut bot = GodfishBotBuilder::new()
+    let commands = GodfishBotBuilder::new()
         .rand_text(
             "explode",
             "/explode [target]",
@@ -115,31 +137,7 @@ async fn main() -> Result<()> {
         .image("bullyback", "bullyback.jpg")
         .image("tease", "tease.jpg")
         .image("flashbacks", "flashback.jpg")
-        .other(
-            "cn",
-            "Get a fact about Chuck Norris. (Powered by http://www.icndb.com)",
-            None,
-        )
-        .other(
-            "trump",
-            "Get a Donald Trump quote. Powered by https://whatdoestrumpthink.com",
-            None,
-        )
-        .other(
-            "dadjoke",
-            "Get a random dad joke from https://icanhazdadjoke.com/api",
-            None,
-        )
-        .other(
-            "catfact",
-            "Get a random cat fact from https://cat-fact.herokuapp.com",
-            None,
-        )
-        .other(
-            "funfact",
-            "Get a useless fact from https://uselessfacts.jsph.pl",
-            None,
-        )
+        .api_commands(&apis)
         .other(
             "doggo",
             "Get a random doggo from teh interwebs (may be filtered by breed)",
@@ -150,77 +148,102 @@ async fn main() -> Result<()> {
             "Test compatibility based on names. Totally scientifically correct!",
             Some(love_test::USAGE),
         )
-        .other("flausch", "Get a fluffy bunny gif", None)
-        .build(tbot::Bot::from_env("BOT_TOKEN"))
-        .await?;
-    info!("Registering custom commands...");
-    bot.command("testlove", love_test::handler);
-    let client = Client::new();
-    let mut bot = bot.into_stateful(client.clone());
-    bot.commands(["cn", "chucknorris"], |ctx, client| async move {
-        simple_api(
-            ctx,
-            &client,
-            "http://api.icndb.com/jokes/random?escape=javascript",
-            "joke",
-            Some("value"),
+        .other(
+            "flausch",
+            "Get a fluffy bunny gif (a specific one, or as an actual GIF)",
+            Some("/flausch [id] [gif]"),
         )
-        .await;
-    });
-    bot.command("trump", |ctx, client| async move {
-        simple_api(
-            ctx,
-            &client,
-            "https://api.whatdoestrumpthink.com/api/v1/quotes/random",
-            "message",
-            None,
+        .other(
+            "cat",
+            "Get a random cat (may be filtered by breed)",
+            Some("/cat [breed]"),
         )
-        .await;
-    });
-    bot.command("catfact", |ctx, client| async move {
-        simple_api(
-            ctx,
-            &client,
-            "https://cat-fact.herokuapp.com/facts/random",
-            "text",
-            None,
+        .other("lovetop", "Show the best couples tested here", None)
+        .other(
+            "lovehistory",
+            "Show someone's best matches",
+            Some(love_history::HISTORY_USAGE),
         )
-        .await;
-    });
-    bot.command("funfact", |ctx, client| async move {
-        simple_api(
-            ctx,
-            &client,
-            "https://uselessfacts.jsph.pl/random.json?language=en",
-            "text",
-            None,
+        .other(
+            "couple",
+            "Pick today's couple among the active members of the chat",
+            Some(couple::USAGE),
         )
-        .await;
-    });
-    bot.command("dadjoke", |ctx, client| async move {
-        let msg = match fetch_dad_joke(&client).await {
-            Ok(msg) => msg,
-            Err(error) => {
-                error!(?error, "error during API request");
-                error.to_string()
-            }
-        };
-        ctx.send_message(msg).call().await.log_err();
-    });
-    // Doggo command
-    let breeds = doggo::fetch_breeds(&client).await.unwrap_or_else(|error| {
-        error!(?error, "Error loading doggo breeds");
-        error!("Using empty breed list");
-        Default::default()
-    });
-    let img_id_map = Mutex::new(HashMap::<String, file::Id>::new());
-    let mut bot = bot.with_other_state((client.clone(), breeds, img_id_map));
-    bot.command("doggo", doggo::doggo_handler);
-    bot.command("breeds", doggo::breeds_handler);
-    // Flausch command
-    let img_id_map = Mutex::new(HashMap::<String, file::Id>::new());
-    let mut bot = bot.with_other_state((client, img_id_map));
-    bot.command("flausch", flausch::handler);
+        .other("fox", "Get a random fox", None)
+        .other("duck", "Get a random duck", None)
+        .other("breeds", "List doggo breeds", Some("/breeds [filter]"))
+        .other("catbreeds", "List cat breeds", Some("/catbreeds [filter]"))
+        .preprocess_images(ImageLimits::from_env()?);
+    if std::env::args().nth(1).as_deref() == Some("sticker-set") {
+        info!("Syncing sticker set...");
+        let images = commands.image_paths().await?;
+        let config = stickers::StickerSetConfig::from_env()?;
+        return stickers::sync_sticker_set(&make_bot()?, &images, &config).await;
+    }
+    let mut bot = commands.build(make_bot()?).await?;
+    info!("Registering custom commands...");
+    let members = Members::default();
+    members::register(&mut bot, members.clone());
+    let love_history = std::env::var("LOVE_HISTORY")
+        .unwrap_or_else(|_| "data/love_history.json".into())
+        .into();
+    let love_history = LoveHistory::load(love_history).await?;
+    let love_cards = match std::env::var("LOVE_CARDS") {
+        Ok(val) => val.parse().wrap_err("Invalid LOVE_CARDS")?,
+        Err(_) => false,
+    };
+    love_test::register(&mut bot, members.clone(), love_history.clone(), love_cards);
+    love_history::register(&mut bot, love_history, members.clone());
+    couple::register(&mut bot, members, CoupleConfig::from_env()?).await?;
+    let http = Http::new(HttpConfig::from_env()?);
+    let mut bot = bot.into_stateful(http.clone());
+    let pool_config = PoolConfig::from_env()?;
+    api::register(&mut bot, apis, &http, pool_config);
+    // Animal commands
+    let captions = match std::env::var("ANIMAL_CAPTIONS") {
+        Ok(val) => val.parse().wrap_err("Invalid ANIMAL_CAPTIONS")?,
+        Err(_) => true,
+    };
+    let mut animals = Animals::new(captions);
+    let doggo_url = base_url("DOGGO_API_URL", "https://dog.ceo/api");
+    let breeds_config = BreedsConfig::from_env()?;
+    let refresh_interval = breeds_config.refresh_interval;
+    let breeds =
+        Breeds::new(doggo::load_breeds(&http, &doggo_url, &breeds_config.cache_path).await);
+    tokio::spawn(doggo::refresh_breeds(
+        http.clone(),
+        doggo_url.clone(),
+        breeds.clone(),
+        breeds_config,
+    ));
+    let dog_ceo = DogCeo {
+        http: http.clone(),
+        api_url: doggo_url,
+        breeds,
+    };
+    let doggo = Animal::new("doggo", dog_ceo, pool_config);
+    animals.add(doggo.variants_command("breeds", "Doggo breeds"));
+    let bunnies = Bunnies {
+        http: http.clone(),
+        api_url: base_url("FLAUSCH_API_URL", "https://api.bunnies.io/v2"),
+    };
+    animals.add(Animal::new("flausch", bunnies, pool_config));
+    let cat_url = base_url("CAT_API_URL", "https://api.thecatapi.com/v1");
+    let cats = TheCatApi::new(http.clone(), cat_url, std::env::var("CAT_API_KEY").ok()).await;
+    tokio::spawn(cats.clone().refresh_breeds(refresh_interval));
+    let cat = Animal::new("cat", cats, pool_config);
+    animals.add(cat.variants_command("catbreeds", "Cat breeds"));
+    let foxes = RandomFox {
+        http: http.clone(),
+        api_url: base_url("FOX_API_URL", "https://randomfox.ca"),
+    };
+    animals.add(Animal::new("fox", foxes, pool_config));
+    let ducks = RandomDuck {
+        http,
+        api_url: base_url("DUCK_API_URL", "https://random-d.uk/api/v2"),
+    };
+    animals.add(Animal::new("duck", ducks, pool_config));
+    let bot = animal::register(bot, animals);
     info!("Starting event loop...");
     tokio::select! {
         res = bot.polling().start() => { res.unwrap(); }
@@ -229,6 +252,17 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Creates the bot from `BOT_TOKEN`, talking to `BOT_API_URL` if it is set
+/// (e.g. a self-hosted or fake Bot API server).
+fn make_bot() -> Result<tbot::Bot> {
+    let mut builder = tbot::bot::Builder::with_env_token("BOT_TOKEN");
+    if let Ok(url) = std::env::var("BOT_API_URL") {
+        info!(%url, "Using custom Bot API server");
+        builder = builder.server_uri(url.parse().wrap_err("Invalid BOT_API_URL")?);
+    }
+    Ok(builder.build())
+}
+
 trait ResultExt {
     fn log_err(self);
     fn log_err_msg(self, msg: &'static str);
@@ -249,68 +283,3 @@ where
         }
     }
 }
-
-async fn simple_api(
-    ctx: Arc<Command>,
-    client: &Client,
-    url: &str,
-    field: &str,
-    outer_field: Option<&str>,
-) {
-    let res = fetch_json_extract_field(client, url, field, outer_field).await;
-    let msg = match res {
-        Ok(msg) => msg,
-        Err(error) => {
-            error!(?error, "error during API request");
-            error.to_string()
-        }
-    };
-    ctx.send_message(msg).call().await.log_err();
-}
-async fn fetch_json_extract_field(
-    client: &Client,
-    url: &str,
-    field: &str,
-    outer_field: Option<&str>,
-) -> Result<String> {
-    let mut json = client.get(url).send().await?.json::<Value>().await?;
-    if let Some(outer_field) = outer_field {
-        json = json
-            .get(outer_field)
-            .ok_or_else(|| {
-                eyre!(
-                    "outer_field not found: {:?} (json: {:?})",
-                    outer_field,
-                    json
-                )
-            })?
-            .to_owned();
-    }
-    let val = json
-        .get(field)
-        .ok_or_else(|| eyre!("json_field not found: {:?} (json: {:?})", field, json))?;
-    let val = val
-        .as_str()
-        .ok_or_else(|| eyre!("type error: expected string, found value: {:?}", val))?
-        .to_string();
-    Ok(val)
-}
-
-async fn fetch_dad_joke(client: &Client) -> Result<String> {
-    let json = client
-        .get("https://icanhazdadjoke.com/")
-        .header("Accept", "application/json")
-        .header(
-            "User-Agent",
-            "godfishbot-ng (https://github.com/Follpvosten/godfishbot-ng)",
-        )
-        .send()
-        .await?
-        .json::<Value>()
-        .await?;
-    json.get("joke")
-        .ok_or_else(|| eyre!("joke field missing: {:?}", json))?
-        .as_str()
-        .ok_or_else(|| eyre!("joke field not a string, wtf"))
-        .map(str::to_string)
-}
@@ -0,0 +1,101 @@
+//! Users seen in each chat, so that commands can refer to them by username.
+//! The Bot API has no way to look up chat members by username, so we can
+//! only know the ones who have written something since the bot started.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use tbot::{
+    types::{chat, user, User},
+    EventLoop,
+};
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub id: user::Id,
+    pub first_name: String,
+    pub username: Option<String>,
+    pub last_seen: SystemTime,
+}
+impl Member {
+    pub fn new(user: &User) -> Self {
+        Member {
+            id: user.id,
+            first_name: user.first_name.clone(),
+            username: user.username.clone(),
+            last_seen: SystemTime::now(),
+        }
+    }
+
+    /// A clickable mention for HTML messages.
+    pub fn mention(&self) -> String {
+        format!(
+            "<a href=\"tg://user?id={}\">{}</a>",
+            self.id.0,
+            escape_html(&self.first_name)
+        )
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Members(Arc<Mutex<HashMap<chat::Id, HashMap<user::Id, Member>>>>);
+impl Members {
+    /// Remembers that `user` is in the chat (and was active just now).
+    pub fn record(&self, chat: chat::Id, user: &User) {
+        if user.is_bot {
+            return;
+        }
+        let mut chats = self.0.lock().unwrap();
+        chats
+            .entry(chat)
+            .or_default()
+            .insert(user.id, Member::new(user));
+    }
+
+    /// Looks up a member by their (case-insensitive) username, with or
+    /// without the `@`.
+    pub fn find(&self, chat: chat::Id, username: &str) -> Option<Member> {
+        let username = username.trim_start_matches('@');
+        let chats = self.0.lock().unwrap();
+        chats.get(&chat)?.values().find_map(|member| {
+            member
+                .username
+                .as_deref()
+                .filter(|name| name.eq_ignore_ascii_case(username))
+                .map(|_| member.clone())
+        })
+    }
+
+    /// Members who were active since then, ordered by ID.
+    pub fn active_since(&self, chat: chat::Id, since: SystemTime) -> Vec<Member> {
+        let chats = self.0.lock().unwrap();
+        let mut members: Vec<Member> = chats
+            .get(&chat)
+            .into_iter()
+            .flat_map(HashMap::values)
+            .filter(|member| member.last_seen >= since)
+            .cloned()
+            .collect();
+        members.sort_by_key(|member| member.id.0);
+        members
+    }
+}
+
+/// Records the senders of all text messages.
+pub fn register(bot: &mut EventLoop, members: Members) {
+    bot.text(move |ctx| {
+        if let Some(user) = ctx.from.clone().and_then(|from| from.user()) {
+            members.record(ctx.chat.id, &user);
+        }
+        async {}
+    });
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
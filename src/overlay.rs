@@ -0,0 +1,250 @@
+use std::{collections::HashMap, io::Cursor, path::Path};
+
+use color_eyre::{eyre::WrapErr, Result};
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, ImageOutputFormat, RgbaImage,
+};
+use serde::Deserialize;
+use tbot::{
+    contexts::{methods::Message, Command},
+    types::{input_file::Photo, message, user},
+    Bot,
+};
+use tokio::{fs, task};
+
+use crate::persist::load_json;
+
+/// Where and how a replied user's profile picture is put into an image.
+///
+/// All coordinates are relative to the template image, so the same
+/// placement can be used for a whole folder of differently sized images.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Template {
+    /// Horizontal center of the avatar, as a fraction of the image width
+    pub x: f32,
+    /// Vertical center of the avatar, as a fraction of the image height
+    pub y: f32,
+    /// Edge length of the avatar, as a fraction of the image's shorter side
+    pub size: f32,
+    #[serde(default)]
+    pub mask: Mask,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mask {
+    None,
+    #[default]
+    Circle,
+    Star,
+}
+
+pub type Templates = HashMap<String, Template>;
+
+/// Reads the templates; without a file there are none.
+pub async fn load_templates(path: &Path) -> Result<Templates> {
+    load_json(path).await
+}
+
+/// Downloads the biggest version of the user's current profile picture.
+/// Returns `None` if the user doesn't have one (or hides it from bots).
+pub async fn fetch_avatar(bot: &Bot, user_id: user::Id) -> Result<Option<Vec<u8>>> {
+    let photos = bot
+        .get_user_profile_photos(user_id)
+        .limit(1)
+        .call()
+        .await
+        .wrap_err("Error fetching profile photos")?;
+    let biggest = photos
+        .photos
+        .into_iter()
+        .next()
+        .and_then(|sizes| sizes.into_iter().max_by_key(|size| size.width));
+    let biggest = match biggest {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+    let file = bot
+        .get_file(biggest.file_id)
+        .call()
+        .await
+        .wrap_err("Error fetching profile photo file")?;
+    let bytes = bot
+        .download_file(&file)
+        .await
+        .wrap_err("Error downloading profile photo")?;
+    Ok(Some(bytes))
+}
+
+/// Sends `background` with `target`'s profile picture put into it.
+pub async fn send_overlay(
+    ctx: &Command,
+    background: &Path,
+    template: &Template,
+    target: user::Id,
+    reply_to: message::Id,
+) -> Result<()> {
+    let avatar = match fetch_avatar(&ctx.bot, target).await? {
+        Some(avatar) => avatar,
+        None => {
+            ctx.send_message_in_reply("I can't see their profile picture :(")
+                .call()
+                .await?;
+            return Ok(());
+        }
+    };
+    let background = fs::read(background)
+        .await
+        .wrap_err_with(|| format!("Error loading {:?}", background))?;
+    let template = template.clone();
+    let bytes = task::spawn_blocking(move || compose(&background, &avatar, &template)).await??;
+    ctx.send_photo(Photo::with_bytes(bytes))
+        .in_reply_to(reply_to)
+        .call()
+        .await?;
+    Ok(())
+}
+
+/// Puts `avatar` into `background` according to `template`; returns a JPEG.
+pub fn compose(background: &[u8], avatar: &[u8], template: &Template) -> Result<Vec<u8>> {
+    let mut background = image::load_from_memory(background)
+        .wrap_err("Error decoding template image")?
+        .to_rgba8();
+    let avatar = image::load_from_memory(avatar).wrap_err("Error decoding avatar")?;
+    let (width, height) = background.dimensions();
+    let size = ((width.min(height) as f32 * template.size).round() as u32).max(1);
+    let mut avatar = avatar
+        .resize_to_fill(size, size, FilterType::Lanczos3)
+        .to_rgba8();
+    apply_mask(&mut avatar, template.mask);
+    let x = (width as f32 * template.x).round() as i64 - i64::from(size / 2);
+    let y = (height as f32 * template.y).round() as i64 - i64::from(size / 2);
+    imageops::overlay(&mut background, &avatar, x, y);
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba8(background)
+        .to_rgb8()
+        .write_to(&mut out, ImageOutputFormat::Jpeg(90))
+        .wrap_err("Error encoding result")?;
+    Ok(out.into_inner())
+}
+
+fn apply_mask(img: &mut RgbaImage, mask: Mask) {
+    let size = img.width() as f32;
+    let center = size / 2.0;
+    let star = star_polygon(center, center, center);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let inside = match mask {
+            Mask::None => true,
+            Mask::Circle => (px - center).powi(2) + (py - center).powi(2) <= center.powi(2),
+            Mask::Star => point_in_polygon(px, py, &star),
+        };
+        if !inside {
+            pixel[3] = 0;
+        }
+    }
+}
+
+/// The ten corners of a five-pointed star, pointing upwards.
+fn star_polygon(cx: f32, cy: f32, radius: f32) -> Vec<(f32, f32)> {
+    use std::f32::consts::PI;
+    (0..10)
+        .map(|i| {
+            let r = if i % 2 == 0 { radius } else { radius * 0.45 };
+            let angle = -PI / 2.0 + i as f32 * PI / 5.0;
+            (cx + r * angle.cos(), cy + r * angle.sin())
+        })
+        .collect()
+}
+
+fn point_in_polygon(x: f32, y: f32, polygon: &[(f32, f32)]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for (i, &(xi, yi)) in polygon.iter().enumerate() {
+        let (xj, yj) = polygon[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    fn png(width: u32, height: u32, color: Rgba<u8>) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, color))
+            .write_to(&mut out, ImageOutputFormat::Png)
+            .unwrap();
+        out.into_inner()
+    }
+
+    /// Whether the pixel is about `color`, allowing for JPEG artifacts.
+    fn is(img: &image::RgbImage, x: u32, y: u32, color: Rgba<u8>) -> bool {
+        let pixel = img.get_pixel(x, y);
+        (0..3).all(|i| (i16::from(pixel[i]) - i16::from(color[i])).abs() < 24)
+    }
+
+    fn masked(mask: Mask) -> RgbaImage {
+        let mut img = RgbaImage::from_pixel(100, 100, RED);
+        apply_mask(&mut img, mask);
+        img
+    }
+
+    #[test]
+    fn avatar_is_put_at_the_template_position() {
+        let compose = |mask| {
+            let template = Template {
+                x: 0.5,
+                y: 0.5,
+                size: 0.5,
+                mask,
+            };
+            let jpeg = compose(&png(200, 200, BLUE), &png(30, 30, RED), &template).unwrap();
+            image::load_from_memory(&jpeg).unwrap().to_rgb8()
+        };
+        let img = compose(Mask::None);
+        assert_eq!(img.dimensions(), (200, 200));
+        assert!(is(&img, 100, 100, RED));
+        assert!(is(&img, 60, 60, RED));
+        assert!(is(&img, 20, 20, BLUE));
+        assert!(is(&img, 180, 100, BLUE));
+        // The corners of the avatar are cut off
+        let img = compose(Mask::Circle);
+        assert!(is(&img, 100, 100, RED));
+        assert!(is(&img, 60, 60, BLUE));
+    }
+
+    #[test]
+    fn circle_mask_keeps_the_inscribed_circle() {
+        let img = masked(Mask::Circle);
+        assert_eq!(img.get_pixel(50, 50)[3], 255);
+        assert_eq!(img.get_pixel(0, 50)[3], 255);
+        assert_eq!(img.get_pixel(50, 99)[3], 255);
+        assert_eq!(img.get_pixel(79, 19)[3], 255);
+        assert_eq!(img.get_pixel(0, 0)[3], 0);
+        assert_eq!(img.get_pixel(90, 90)[3], 0);
+    }
+
+    #[test]
+    fn star_mask_keeps_an_upright_star() {
+        let img = masked(Mask::Star);
+        assert_eq!(img.get_pixel(50, 50)[3], 255);
+        // The top point, but not the notch next to it
+        assert_eq!(img.get_pixel(50, 5)[3], 255);
+        assert_eq!(img.get_pixel(79, 19)[3], 0);
+        // No point downwards
+        assert_eq!(img.get_pixel(50, 95)[3], 0);
+        assert_eq!(img.get_pixel(0, 0)[3], 0);
+        assert_eq!(masked(Mask::None).get_pixel(0, 0)[3], 255);
+    }
+}
@@ -0,0 +1,29 @@
+//! The JSON files the bot keeps its data and settings in.
+
+use std::{io, path::Path};
+
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::fs;
+
+/// Reads JSON from `path`; a missing file counts as empty.
+pub async fn load_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    let json = match fs::read(path).await {
+        Ok(json) => json,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(error) => return Err(error).wrap_err_with(|| format!("Error reading {:?}", path)),
+    };
+    serde_json::from_slice(&json).wrap_err_with(|| format!("Error parsing {:?}", path))
+}
+
+/// Writes `value` as JSON to `path`, creating its folder if needed.
+pub async fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    // Write to a temporary file first so a crash can't leave a broken one
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::VecDeque,
+    env, fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use tokio::{
+    sync::{Mutex, Notify},
+    time,
+};
+use tracing::{debug, warn};
+
+const MIN_SIZE: usize = 5;
+const MAX_SIZE: usize = 20;
+/// How long to wait before trying again after a failed fetch
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    pub size: usize,
+    pub max_age: Duration,
+}
+impl PoolConfig {
+    /// `PREFETCH_SIZE` (5 to 20, default 5) and `PREFETCH_MAX_AGE_SECS`
+    /// (at least 1, default one hour).
+    pub fn from_env() -> Result<Self> {
+        let size = match env::var("PREFETCH_SIZE") {
+            Ok(val) => val.parse().wrap_err("Invalid PREFETCH_SIZE")?,
+            Err(_) => MIN_SIZE,
+        };
+        let max_age = match env::var("PREFETCH_MAX_AGE_SECS") {
+            Ok(val) => val.parse().wrap_err("Invalid PREFETCH_MAX_AGE_SECS")?,
+            Err(_) => 60 * 60,
+        };
+        // Everything would be stale right away, so the pool would refill forever
+        if max_age == 0 {
+            bail!("PREFETCH_MAX_AGE_SECS must be at least 1");
+        }
+        Ok(PoolConfig {
+            size: size.clamp(MIN_SIZE, MAX_SIZE),
+            max_age: Duration::from_secs(max_age),
+        })
+    }
+}
+
+/// A buffer of prefetched results which is refilled in the background.
+pub struct Pool<T> {
+    name: &'static str,
+    config: PoolConfig,
+    items: Mutex<VecDeque<(Instant, T)>>,
+    taken: Notify,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Creates the pool and spawns a task which keeps it filled using `fetch`.
+    pub fn spawn<F, Fut, E>(name: &'static str, config: PoolConfig, fetch: F) -> Arc<Self>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+        E: fmt::Debug + Send + 'static,
+    {
+        let pool = Arc::new(Pool {
+            name,
+            config,
+            items: Mutex::new(VecDeque::with_capacity(config.size)),
+            taken: Notify::new(),
+        });
+        tokio::spawn(pool.clone().fill(fetch));
+        pool
+    }
+
+    /// Takes the oldest item that isn't stale yet, if there is one.
+    pub async fn take(&self) -> Option<T> {
+        let mut items = self.items.lock().await;
+        let result = loop {
+            match items.pop_front() {
+                Some((fetched, item)) if fetched.elapsed() < self.config.max_age => {
+                    break Some(item)
+                }
+                Some(_) => debug!(pool = self.name, "discarding stale item"),
+                None => break None,
+            }
+        };
+        self.taken.notify_one();
+        result
+    }
+
+    async fn fill<F, Fut, E>(self: Arc<Self>, fetch: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>> + 'static,
+        E: fmt::Debug + Send + 'static,
+    {
+        loop {
+            let missing = {
+                let mut items = self.items.lock().await;
+                let max_age = self.config.max_age;
+                items.retain(|(fetched, _)| fetched.elapsed() < max_age);
+                self.config.size.saturating_sub(items.len())
+            };
+            if missing == 0 {
+                // Wake up either when something was taken or when the oldest
+                // items are about to go stale.
+                let _ = time::timeout(self.config.max_age / 2, self.taken.notified()).await;
+                continue;
+            }
+            match fetch().await {
+                Ok(item) => self.items.lock().await.push_back((Instant::now(), item)),
+                Err(error) => {
+                    warn!(?error, pool = self.name, "error prefetching");
+                    time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+use tokio::{fs, task};
+use tracing::{info, warn};
+
+/// Telegram rejects photos bigger than 10 MB.
+const MAX_PHOTO_SIZE: usize = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_dimension: u32,
+    pub jpeg_quality: u8,
+}
+impl ImageLimits {
+    /// Preprocessing is enabled by setting `IMAGE_MAX_DIMENSION`;
+    /// `IMAGE_JPEG_QUALITY` defaults to 85.
+    pub fn from_env() -> Result<Option<Self>> {
+        let max_dimension = match env::var("IMAGE_MAX_DIMENSION") {
+            Ok(val) => val.parse().wrap_err("Invalid IMAGE_MAX_DIMENSION")?,
+            Err(_) => return Ok(None),
+        };
+        let jpeg_quality = match env::var("IMAGE_JPEG_QUALITY") {
+            Ok(val) => val.parse().wrap_err("Invalid IMAGE_JPEG_QUALITY")?,
+            Err(_) => 85,
+        };
+        Ok(Some(ImageLimits {
+            max_dimension,
+            jpeg_quality,
+        }))
+    }
+}
+
+/// Normalises all given images which are too big or not JPEGs and returns
+/// the processed bytes; images which are fine as they are aren't included.
+pub async fn preprocess_images(
+    paths: Vec<PathBuf>,
+    limits: ImageLimits,
+) -> HashMap<PathBuf, Vec<u8>> {
+    let mut processed = HashMap::new();
+    for path in paths {
+        let bytes = match fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                warn!(?error, ?path, "error loading image for preprocessing");
+                continue;
+            }
+        };
+        let task_path = path.clone();
+        match task::spawn_blocking(move || normalise(&task_path, &bytes, limits)).await {
+            Ok(Ok(Some(bytes))) => {
+                processed.insert(path, bytes);
+            }
+            Ok(Ok(None)) => (),
+            Ok(Err(error)) => warn!(?error, ?path, "error preprocessing image"),
+            Err(error) => warn!(?error, ?path, "image preprocessing panicked"),
+        }
+    }
+    processed
+}
+
+fn normalise(path: &Path, bytes: &[u8], limits: ImageLimits) -> Result<Option<Vec<u8>>> {
+    let format = image::guess_format(bytes).wrap_err("Unknown image format")?;
+    let img = image::load_from_memory_with_format(bytes, format)?;
+    let (width, height) = img.dimensions();
+    let too_big = width.max(height) > limits.max_dimension;
+    let wrong_format = format != ImageFormat::Jpeg;
+    let too_heavy = bytes.len() > MAX_PHOTO_SIZE;
+    if !(too_big || wrong_format || too_heavy) {
+        return Ok(None);
+    }
+    let img = if too_big {
+        img.resize(
+            limits.max_dimension,
+            limits.max_dimension,
+            FilterType::Lanczos3,
+        )
+    } else {
+        img
+    };
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(img.to_rgb8())
+        .write_to(&mut out, ImageOutputFormat::Jpeg(limits.jpeg_quality))?;
+    let out = out.into_inner();
+    info!(
+        ?path,
+        from = ?(width, height),
+        to = ?img.dimensions(),
+        ?format,
+        old_size = bytes.len(),
+        new_size = out.len(),
+        "normalised image"
+    );
+    Ok(Some(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ImageLimits = ImageLimits {
+        max_dimension: 100,
+        jpeg_quality: 85,
+    };
+
+    fn encode(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut out, format)
+            .unwrap();
+        out.into_inner()
+    }
+
+    fn normalised(bytes: &[u8]) -> Option<DynamicImage> {
+        let out = normalise(Path::new("test"), bytes, LIMITS).unwrap()?;
+        assert_eq!(image::guess_format(&out).unwrap(), ImageFormat::Jpeg);
+        Some(image::load_from_memory(&out).unwrap())
+    }
+
+    #[test]
+    fn oversized_images_are_downscaled() {
+        let img = normalised(&encode(400, 200, ImageOutputFormat::Jpeg(85))).unwrap();
+        assert_eq!(img.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn other_formats_are_turned_into_jpegs() {
+        let img = normalised(&encode(80, 60, ImageOutputFormat::Png)).unwrap();
+        assert_eq!(img.dimensions(), (80, 60));
+    }
+
+    #[test]
+    fn small_jpegs_are_left_alone() {
+        assert!(normalised(&encode(80, 60, ImageOutputFormat::Jpeg(85))).is_none());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{env, io::Cursor, path::PathBuf};
+
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use image::{imageops::FilterType, ImageOutputFormat};
+use tbot::{
+    errors::MethodCall,
+    types::{input_file::PngSticker, user},
+    Bot,
+};
+use tokio::{fs, task};
+use tracing::{error, info, warn};
+
+/// Telegram wants static stickers to be PNGs of at most 512 KB.
+const MAX_STICKER_SIZE: usize = 512 * 1024;
+/// ...with one side being exactly 512 px.
+const STICKER_DIMENSION: u32 = 512;
+
+/// Which sticker set to sync and how.
+#[derive(Debug, Clone)]
+pub struct StickerSetConfig {
+    /// The user the set belongs to
+    pub owner: user::Id,
+    /// The emoji every sticker is filed under
+    pub emoji: String,
+    /// The set's name, without the `_by_<bot username>` Telegram requires
+    pub name: String,
+}
+impl StickerSetConfig {
+    /// `STICKER_SET_OWNER` (required), `STICKER_EMOJI` (default 🐟) and
+    /// `STICKER_SET_NAME` (default `godfish`).
+    pub fn from_env() -> Result<Self> {
+        let owner = env::var("STICKER_SET_OWNER")
+            .wrap_err("STICKER_SET_OWNER must be set to the id of the set's owner")?
+            .parse()
+            .map(user::Id)
+            .wrap_err("STICKER_SET_OWNER is not a valid user id")?;
+        Ok(StickerSetConfig {
+            owner,
+            emoji: env::var("STICKER_EMOJI").unwrap_or_else(|_| "🐟".into()),
+            name: env::var("STICKER_SET_NAME").unwrap_or_else(|_| "godfish".into()),
+        })
+    }
+}
+
+/// Creates the bot's sticker set from `images` or, if it already exists,
+/// replaces its contents.
+pub async fn sync_sticker_set(
+    bot: &Bot,
+    images: &[PathBuf],
+    config: &StickerSetConfig,
+) -> Result<()> {
+    let StickerSetConfig { owner, emoji, .. } = config;
+    let me = bot
+        .get_me()
+        .call()
+        .await
+        .wrap_err("Error fetching bot info")?;
+    let username = me
+        .user
+        .username
+        .ok_or_else(|| eyre!("Bot has no username"))?;
+    let name = format!("{}_by_{}", config.name, username);
+
+    let mut stickers = Vec::with_capacity(images.len());
+    for path in images {
+        match prepare_sticker(path.clone()).await {
+            Ok(bytes) => stickers.push(bytes),
+            Err(error) => warn!(?error, ?path, "skipping image"),
+        }
+    }
+    // Don't touch an existing set if there's nothing to replace it with
+    if stickers.is_empty() {
+        bail!("No usable images");
+    }
+    let mut stickers = stickers.into_iter();
+
+    let old_stickers = match bot.get_sticker_set(&name).call().await {
+        Ok(set) => set.stickers,
+        Err(MethodCall::RequestError {
+            description,
+            error_code: 400,
+            ..
+        }) if description.contains("STICKERSET_INVALID") => {
+            let first = stickers.next().expect("checked above");
+            info!(%name, "Creating sticker set");
+            bot.create_new_sticker_set(
+                *owner,
+                &name,
+                "GodfishBot",
+                PngSticker::with_bytes(first),
+                emoji,
+            )
+            .call()
+            .await
+            .wrap_err("Error creating sticker set")?;
+            Vec::new()
+        }
+        Err(error) => return Err(error).wrap_err("Error fetching sticker set"),
+    };
+    // A set can't hold both generations at once, so keep only one old sticker
+    // around (a set can't be empty either) until the new ones are in.
+    let mut old_stickers = old_stickers.into_iter();
+    let last_old = old_stickers.next();
+    for sticker in old_stickers {
+        bot.delete_sticker_from_set(sticker.file_id)
+            .call()
+            .await
+            .wrap_err("Error removing old sticker")?;
+    }
+    let mut added = 0;
+    for bytes in stickers {
+        let result = bot
+            .add_sticker_to_set(*owner, &name, PngSticker::with_bytes(bytes), emoji)
+            .call()
+            .await;
+        if let Err(error) = result {
+            error!(
+                %name,
+                added,
+                "Sticker set is only partly updated, run sticker-set again"
+            );
+            return Err(error).wrap_err("Error adding sticker");
+        }
+        added += 1;
+    }
+    if let Some(sticker) = last_old {
+        bot.delete_sticker_from_set(sticker.file_id)
+            .call()
+            .await
+            .wrap_err("Error removing old sticker")?;
+    }
+    info!(%name, added, "Sticker set is up to date: https://t.me/addstickers/{}", name);
+    Ok(())
+}
+
+async fn prepare_sticker(path: PathBuf) -> Result<Vec<u8>> {
+    let bytes = fs::read(&path).await?;
+    task::spawn_blocking(move || to_sticker_png(&bytes)).await?
+}
+
+/// Scales the image so its longer side is 512 px and encodes it as PNG.
+fn to_sticker_png(bytes: &[u8]) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(bytes)
+        .wrap_err("Error decoding image")?
+        .resize(STICKER_DIMENSION, STICKER_DIMENSION, FilterType::Lanczos3);
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, ImageOutputFormat::Png)
+        .wrap_err("Error encoding sticker")?;
+    let out = out.into_inner();
+    if out.len() > MAX_STICKER_SIZE {
+        bail!("sticker too big: {} bytes", out.len());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_util::bot_api::FakeBotApi;
+
+    const SET_NAME: &str = "test_by_godfish_test_bot";
+
+    fn config() -> StickerSetConfig {
+        StickerSetConfig {
+            owner: user::Id(1),
+            emoji: "🐟".into(),
+            name: "test".into(),
+        }
+    }
+
+    fn stars() -> Vec<PathBuf> {
+        vec!["res/stars/star0.jpg".into(), "res/stars/star1.jpg".into()]
+    }
+
+    fn sticker(file_id: &str) -> Value {
+        json!({
+            "file_id": file_id,
+            "file_unique_id": file_id,
+            "type": "regular",
+            "width": 512,
+            "height": 512,
+            "is_animated": false,
+            "is_video": false,
+        })
+    }
+
+    #[tokio::test]
+    async fn missing_set_is_created() {
+        let tg = FakeBotApi::start().await;
+        tg.fail("getStickerSet", 400, "Bad Request: STICKERSET_INVALID");
+
+        sync_sticker_set(&tg.bot(), &stars(), &config())
+            .await
+            .unwrap();
+        let call = tg.expect("getStickerSet").await;
+        assert_eq!(call.param("name").as_deref(), Some(SET_NAME));
+        let call = tg.expect("createNewStickerSet").await;
+        assert_eq!(call.param("name").as_deref(), Some(SET_NAME));
+        assert_eq!(call.param("user_id").as_deref(), Some("1"));
+        assert!(call.files.contains_key("png_sticker"));
+        let call = tg.expect("addStickerToSet").await;
+        assert!(call.files.contains_key("png_sticker"));
+        tg.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn existing_set_is_replaced() {
+        let tg = FakeBotApi::start().await;
+        tg.answer(
+            "getStickerSet",
+            json!({
+                "name": SET_NAME,
+                "title": "GodfishBot",
+                "sticker_type": "regular",
+                "is_animated": false,
+                "is_video": false,
+                "contains_masks": false,
+                "stickers": [sticker("old1"), sticker("old2")],
+            }),
+        );
+
+        sync_sticker_set(&tg.bot(), &stars(), &config())
+            .await
+            .unwrap();
+        tg.expect("getStickerSet").await;
+        let call = tg.expect("deleteStickerFromSet").await;
+        assert_eq!(call.param("sticker").as_deref(), Some("old2"));
+        tg.expect("addStickerToSet").await;
+        tg.expect("addStickerToSet").await;
+        // The last old sticker only goes once the set has new ones
+        let call = tg.expect("deleteStickerFromSet").await;
+        assert_eq!(call.param("sticker").as_deref(), Some("old1"));
+        tg.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn set_is_left_alone_without_usable_images() {
+        let tg = FakeBotApi::start().await;
+
+        let images = vec!["res/txt/couple.txt".into(), "nonexistent.jpg".into()];
+        let result = sync_sticker_set(&tg.bot(), &images, &config()).await;
+        assert!(result.is_err());
+        tg.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn other_errors_dont_create_a_set() {
+        let tg = FakeBotApi::start().await;
+        tg.fail("getStickerSet", 429, "Too Many Requests: retry after 5");
+
+        let result = sync_sticker_set(&tg.bot(), &stars(), &config()).await;
+        assert!(result.is_err());
+        tg.expect("getStickerSet").await;
+        tg.expect_nothing().await;
+    }
+}
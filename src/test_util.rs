@@ -0,0 +1,83 @@
+//! Helpers for tests talking to a mock HTTP server.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use crate::http::{Http, HttpConfig};
+
+pub mod bot_api;
+
+/// Reads a canned response from `tests/fixtures/`.
+pub fn fixture(name: &str) -> String {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read_to_string(&path).unwrap_or_else(|error| panic!("{}: {}", path, error))
+}
+
+/// Answers GET requests to `route` with `body` as JSON.
+pub async fn mount(server: &MockServer, route: &str, status: u16, body: &str) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(ResponseTemplate::new(status).set_body_raw(body, "application/json"))
+        .mount(server)
+        .await;
+}
+
+/// Like [`mount`], but only answers after `delay`.
+pub async fn mount_delayed(server: &MockServer, route: &str, body: &str, delay: Duration) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(body, "application/json")
+                .set_delay(delay),
+        )
+        .mount(server)
+        .await;
+}
+
+/// A client that fails fast: short timeout, no retries, no circuit breaking.
+pub fn test_http() -> Http {
+    Http::new(HttpConfig {
+        timeout: Duration::from_millis(200),
+        retries: 0,
+        failure_threshold: u32::MAX,
+        cooldown: Duration::ZERO,
+    })
+}
+
+/// A file in the temp dir for a test to write to; it's removed again when
+/// this is dropped, even if the test fails.
+pub struct TempPath(PathBuf);
+impl TempPath {
+    /// `name` has to be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        let file = format!("godfish-{}-{}.json", name, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let _ = std::fs::remove_file(&path);
+        TempPath(path)
+    }
+}
+impl Deref for TempPath {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
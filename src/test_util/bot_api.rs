@@ -0,0 +1,548 @@
+//! A fake Telegram Bot API server, so handlers can be tested end to end.
+//!
+//! ```ignore
+//! let tg = FakeBotApi::start().await;
+//! tg.run(GodfishBotBuilder::new().build(tg.bot()).await?);
+//! let group = tg.group("Testers");
+//! tg.user("Alice").sends("/hug Bob").in_chat(&group).send();
+//! let call = tg.expect("sendMessage").await;
+//! assert!(call.text().contains("Bob"));
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map, Value};
+use tokio::{sync::Notify, task::JoinHandle, time};
+use wiremock::{matchers::path_regex, Mock, MockServer, Request, Respond, ResponseTemplate};
+
+const BOT_ID: i64 = 4242;
+const BOT_USERNAME: &str = "godfish_test_bot";
+/// How long to wait for the bot to react to an update.
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bookkeeping calls which aren't interesting to tests.
+const IGNORED_METHODS: &[&str] = &["getMe", "getUpdates", "deleteWebhook"];
+
+#[derive(Default)]
+struct State {
+    updates: Mutex<VecDeque<Value>>,
+    calls: Mutex<VecDeque<Call>>,
+    new_call: Notify,
+    chats: Mutex<HashMap<i64, Value>>,
+    /// Answers set up with [`FakeBotApi::answer`] and [`FakeBotApi::fail`],
+    /// by method
+    canned: Mutex<HashMap<String, VecDeque<Result<Value, (u16, String)>>>>,
+    /// Contents of the files the bot can download, by file path
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    next_id: AtomicI64,
+}
+impl State {
+    fn next_id(&self) -> i64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// A method call the bot made.
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub method: String,
+    pub params: Map<String, Value>,
+    /// Files uploaded along with the call, by field name
+    pub files: BTreeMap<String, Vec<u8>>,
+    /// What the fake server answered
+    pub result: Value,
+}
+impl Call {
+    /// A parameter as a string, whether it was sent as JSON or as a form.
+    pub fn param(&self, name: &str) -> Option<String> {
+        self.params.get(name).map(|value| match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+    }
+    pub fn text(&self) -> String {
+        self.param("text")
+            .unwrap_or_else(|| panic!("{} has no text: {:?}", self.method, self))
+    }
+    /// The file ID of the media in the answer, e.g. for checking caching.
+    pub fn file_id(&self) -> String {
+        [
+            "photo",
+            "voice",
+            "animation",
+            "video",
+            "video_note",
+            "sticker",
+            "document",
+        ]
+        .iter()
+        .find_map(|kind| match &self.result[kind] {
+            Value::Array(sizes) => sizes[0]["file_id"].as_str(),
+            media => media["file_id"].as_str(),
+        })
+        .unwrap_or_else(|| panic!("{} didn't send any media: {:?}", self.method, self))
+        .to_string()
+    }
+}
+
+pub struct FakeBotApi {
+    server: MockServer,
+    state: Arc<State>,
+    event_loop: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl FakeBotApi {
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        let state = Arc::new(State::default());
+        Mock::given(path_regex(r"^/bot[^/]+/\w+$"))
+            .respond_with(Responder(state.clone()))
+            .mount(&server)
+            .await;
+        Mock::given(path_regex(r"^/file/bot[^/]+/.+$"))
+            .respond_with(Download(state.clone()))
+            .mount(&server)
+            .await;
+        FakeBotApi {
+            server,
+            state,
+            event_loop: Mutex::new(None),
+        }
+    }
+
+    /// A bot talking to this server.
+    pub fn bot(&self) -> tbot::Bot {
+        tbot::bot::Builder::with_string_token("4242:fake-token".into())
+            .server_uri(self.server.uri().parse().unwrap())
+            .build()
+    }
+
+    /// Starts polling for updates in the background.
+    pub fn run(&self, event_loop: tbot::EventLoop) {
+        let handle = tokio::spawn(async move {
+            event_loop.polling().start().await.unwrap();
+        });
+        *self.event_loop.lock().unwrap() = Some(handle);
+    }
+
+    pub fn user(&self, name: &str) -> User {
+        User {
+            state: self.state.clone(),
+            id: self.state.next_id(),
+            name: name.into(),
+        }
+    }
+
+    pub fn group(&self, title: &str) -> Chat {
+        let id = -self.state.next_id();
+        let json = json!({ "id": id, "type": "group", "title": title });
+        self.state.chats.lock().unwrap().insert(id, json.clone());
+        Chat { id, json }
+    }
+
+    /// Answers the next `method` call with `result` instead of the default.
+    pub fn answer(&self, method: &str, result: Value) {
+        self.can(method, Ok(result));
+    }
+
+    /// Makes the next `method` call fail like Telegram would.
+    pub fn fail(&self, method: &str, error_code: u16, description: &str) {
+        self.can(method, Err((error_code, description.into())));
+    }
+
+    /// Gives `user` a profile picture for the bot's next
+    /// `getUserProfilePhotos` and `getFile` calls.
+    pub fn profile_photo(&self, user: &User, photo: Vec<u8>) {
+        let file_id = format!("avatar-{}", user.id);
+        let file_path = format!("photos/{}.jpg", file_id);
+        let size = json!({
+            "file_id": file_id,
+            "file_unique_id": file_id,
+            "width": 160,
+            "height": 160,
+        });
+        self.answer(
+            "getUserProfilePhotos",
+            json!({ "total_count": 1, "photos": [[size]] }),
+        );
+        self.answer(
+            "getFile",
+            json!({
+                "file_id": file_id,
+                "file_unique_id": file_id,
+                "file_size": photo.len(),
+                "file_path": file_path,
+            }),
+        );
+        self.state.files.lock().unwrap().insert(file_path, photo);
+    }
+
+    fn can(&self, method: &str, answer: Result<Value, (u16, String)>) {
+        let mut canned = self.state.canned.lock().unwrap();
+        canned.entry(method.into()).or_default().push_back(answer);
+    }
+
+    /// Waits for the bot's next call and checks that it's a `method` call.
+    pub async fn expect(&self, method: &str) -> Call {
+        let call = time::timeout(EXPECT_TIMEOUT, self.next_call())
+            .await
+            .unwrap_or_else(|_| panic!("expected {}, but the bot didn't call anything", method));
+        assert_eq!(call.method, method, "unexpected call: {:?}", call);
+        call
+    }
+
+    /// Checks that the bot doesn't call anything for a while.
+    pub async fn expect_nothing(&self) {
+        if let Ok(call) = time::timeout(Duration::from_millis(300), self.next_call()).await {
+            panic!("expected no calls, got {:?}", call);
+        }
+    }
+
+    async fn next_call(&self) -> Call {
+        loop {
+            let notified = self.state.new_call.notified();
+            if let Some(call) = self.state.calls.lock().unwrap().pop_front() {
+                return call;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for FakeBotApi {
+    fn drop(&mut self) {
+        if let Some(handle) = self.event_loop.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct User {
+    state: Arc<State>,
+    pub id: i64,
+    pub name: String,
+}
+impl User {
+    fn json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "is_bot": false,
+            "first_name": self.name,
+            "username": self.name.to_lowercase(),
+            "language_code": "en",
+        })
+    }
+
+    /// A message from this user, sent to them privately unless
+    /// [`Outgoing::in_chat`] says otherwise.
+    pub fn sends(&self, text: &str) -> Outgoing {
+        let chat = json!({ "id": self.id, "type": "private", "first_name": self.name });
+        self.state
+            .chats
+            .lock()
+            .unwrap()
+            .insert(self.id, chat.clone());
+        let mut message = json!({
+            "message_id": self.state.next_id(),
+            "date": now(),
+            "chat": chat,
+            "from": self.json(),
+            "text": text,
+        });
+        if text.starts_with('/') {
+            let length = text.find(' ').unwrap_or(text.len());
+            message["entities"] = json!([{ "type": "bot_command", "offset": 0, "length": length }]);
+        }
+        Outgoing {
+            state: self.state.clone(),
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Chat {
+    pub id: i64,
+    json: Value,
+}
+
+pub struct Outgoing {
+    state: Arc<State>,
+    message: Value,
+}
+impl Outgoing {
+    pub fn in_chat(mut self, chat: &Chat) -> Self {
+        self.message["chat"] = chat.json.clone();
+        self
+    }
+    pub fn replying_to(mut self, message: &SentMessage) -> Self {
+        self.message["reply_to_message"] = message.json.clone();
+        self
+    }
+    /// Hands the message to the bot with its next `getUpdates`.
+    pub fn send(self) -> SentMessage {
+        let update = json!({ "update_id": self.state.next_id(), "message": self.message });
+        self.state.updates.lock().unwrap().push_back(update);
+        SentMessage { json: self.message }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    json: Value,
+}
+impl SentMessage {
+    pub fn id(&self) -> i64 {
+        self.json["message_id"].as_i64().unwrap()
+    }
+}
+
+struct Responder(Arc<State>);
+
+impl Respond for Responder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let state = &self.0;
+        let method = request.url.path().rsplit('/').next().unwrap_or_default();
+        if method == "getUpdates" {
+            let updates: Vec<_> = state.updates.lock().unwrap().drain(..).collect();
+            if updates.is_empty() {
+                // Don't let the bot spin while there's nothing to do
+                return ok(json!([])).set_delay(Duration::from_millis(50));
+            }
+            return ok(json!(updates));
+        }
+        let (params, files) = parse_params(request);
+        let canned = state
+            .canned
+            .lock()
+            .unwrap()
+            .get_mut(method)
+            .and_then(VecDeque::pop_front);
+        let (result, response) = match canned {
+            Some(Ok(result)) => (result.clone(), ok(result)),
+            Some(Err((error_code, description))) => {
+                let body = json!({
+                    "ok": false,
+                    "error_code": error_code,
+                    "description": description,
+                });
+                (
+                    Value::Null,
+                    ResponseTemplate::new(error_code).set_body_json(body),
+                )
+            }
+            None => {
+                let result = default_result(state, method, &params);
+                (result.clone(), ok(result))
+            }
+        };
+        if !IGNORED_METHODS.contains(&method) {
+            state.calls.lock().unwrap().push_back(Call {
+                method: method.into(),
+                params,
+                files,
+                result,
+            });
+            state.new_call.notify_one();
+        }
+        response
+    }
+}
+
+/// Serves downloads of the files set up by the tests.
+struct Download(Arc<State>);
+
+impl Respond for Download {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        // `/file/bot<token>/<file path>`
+        let file_path = request.url.path().splitn(4, '/').nth(3).unwrap_or_default();
+        match self.0.files.lock().unwrap().get(file_path) {
+            Some(bytes) => ResponseTemplate::new(200).set_body_bytes(bytes.clone()),
+            None => ResponseTemplate::new(404),
+        }
+    }
+}
+
+/// What Telegram would answer a successful `method` call with.
+fn default_result(state: &State, method: &str, params: &Map<String, Value>) -> Value {
+    match method {
+        "getMe" => json!({
+            "id": BOT_ID,
+            "is_bot": true,
+            "first_name": "GodfishBot",
+            "username": BOT_USERNAME,
+            "can_join_groups": true,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+        }),
+        "getUserProfilePhotos" => json!({ "total_count": 0, "photos": [] }),
+        "sendMediaGroup" => {
+            let media = match &params["media"] {
+                Value::String(json) => serde_json::from_str(json).unwrap(),
+                media => media.clone(),
+            };
+            let count = media.as_array().map_or(0, Vec::len);
+            json!((0..count)
+                .map(|_| sent_message(state, "sendPhoto", params))
+                .collect::<Vec<_>>())
+        }
+        method if method.starts_with("send") && method != "sendChatAction" => {
+            sent_message(state, method, params)
+        }
+        _ => json!(true),
+    }
+}
+
+fn ok(result: Value) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({ "ok": true, "result": result }))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// The message Telegram would answer a `send*` call with.
+fn sent_message(state: &State, method: &str, params: &Map<String, Value>) -> Value {
+    let chat_id = match &params["chat_id"] {
+        Value::String(id) => id.parse().unwrap(),
+        id => id.as_i64().unwrap(),
+    };
+    let chat = state.chats.lock().unwrap().get(&chat_id).cloned();
+    let chat =
+        chat.unwrap_or_else(|| json!({ "id": chat_id, "type": "private", "first_name": "?" }));
+    let mut message = json!({
+        "message_id": state.next_id(),
+        "date": now(),
+        "chat": chat,
+        "from": { "id": BOT_ID, "is_bot": true, "first_name": "GodfishBot" },
+    });
+    let kind: String = method
+        .trim_start_matches("send")
+        .chars()
+        .enumerate()
+        .flat_map(|(i, c)| {
+            let separator = (i > 0 && c.is_ascii_uppercase()).then_some('_');
+            separator.into_iter().chain(Some(c.to_ascii_lowercase()))
+        })
+        .collect();
+    if kind == "message" {
+        message["text"] = params["text"].clone();
+        return message;
+    }
+    // Sent media keep their file ID, uploads and URLs get a new one
+    let file_id = match params.get(&kind) {
+        Some(Value::String(id)) if !id.contains("://") => id.clone(),
+        _ => format!("file-{}", state.next_id()),
+    };
+    let file = json!({ "file_id": file_id, "file_unique_id": file_id });
+    message[&kind] = match kind.as_str() {
+        "photo" => json!([merge(file, json!({ "width": 512, "height": 512 }))]),
+        "animation" | "video" => merge(file, json!({ "width": 512, "height": 512, "duration": 1 })),
+        "video_note" => merge(file, json!({ "length": 512, "duration": 1 })),
+        "voice" | "audio" => merge(file, json!({ "duration": 1 })),
+        "sticker" => merge(
+            file,
+            json!({
+                "type": "regular",
+                "width": 512,
+                "height": 512,
+                "is_animated": false,
+                "is_video": false,
+            }),
+        ),
+        _ => file,
+    };
+    message
+}
+
+fn merge(mut a: Value, b: Value) -> Value {
+    if let (Some(a), Value::Object(b)) = (a.as_object_mut(), b) {
+        a.extend(b);
+    }
+    a
+}
+
+/// Parses a JSON or `multipart/form-data` request body.
+fn parse_params(request: &Request) -> (Map<String, Value>, BTreeMap<String, Vec<u8>>) {
+    let content_type = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.as_str().eq_ignore_ascii_case("content-type"))
+        .map(|(_, values)| values.last().as_str().to_string())
+        .unwrap_or_default();
+    match content_type.split_once("boundary=") {
+        Some((_, boundary)) => parse_multipart(boundary.trim_matches('"'), &request.body),
+        None if request.body.is_empty() => Default::default(),
+        None => (
+            serde_json::from_slice(&request.body).unwrap_or_default(),
+            BTreeMap::new(),
+        ),
+    }
+}
+
+fn parse_multipart(boundary: &str, body: &[u8]) -> (Map<String, Value>, BTreeMap<String, Vec<u8>>) {
+    let mut params = Map::new();
+    let mut files = BTreeMap::new();
+    let delimiter = format!("--{}", boundary);
+    for part in split(body, delimiter.as_bytes()) {
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let header_end = match find(part, b"\r\n\r\n") {
+            Some(end) => end,
+            // The preamble and the closing `--`
+            None => continue,
+        };
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let content = &part[header_end + 4..];
+        let content = content.strip_suffix(b"\r\n").unwrap_or(content);
+        let disposition = headers
+            .lines()
+            .find(|line| line.to_ascii_lowercase().starts_with("content-disposition"))
+            .unwrap_or_default();
+        let name = match attribute(disposition, "name") {
+            Some(name) => name,
+            None => continue,
+        };
+        if attribute(disposition, "filename").is_some() {
+            files.insert(name, content.to_vec());
+        } else {
+            let value = String::from_utf8_lossy(content).into_owned();
+            params.insert(name, Value::String(value));
+        }
+    }
+    (params, files)
+}
+
+fn attribute(header: &str, key: &str) -> Option<String> {
+    header.split(';').map(str::trim).find_map(|pair| {
+        let value = pair.strip_prefix(key)?.strip_prefix('=')?;
+        Some(value.trim_matches('"').to_string())
+    })
+}
+
+fn split<'a>(haystack: &'a [u8], needle: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut rest = haystack;
+    while let Some(pos) = find(rest, needle) {
+        parts.push(&rest[..pos]);
+        rest = &rest[pos + needle.len()..];
+    }
+    parts.push(rest);
+    parts
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}